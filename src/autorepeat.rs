@@ -0,0 +1,328 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    lex::{bit, Event, Key, Millis, Pressed, PRESS_SIZE},
+    parse::{Emit, Rules},
+    pipeline::Stage,
+    report::{append, build_keyboard_report, is_modifier, Overflow},
+};
+
+// Held keys that sat still for the delay resolve right away and repeat at the
+// rate until released or another key goes down. Only a key no further key can
+// change, or a tap under held layer or modifier keys, repeats. Every repeat is
+// the resolved emit built anew, it passes the report hooks of the stages after
+// it and the rule overrides like any report
+pub struct AutoRepeat {
+    delay: Millis,
+    rate: Millis,
+    // keys down since none were, in press order, as the engine stack has them
+    held: Vec<Pressed, PRESS_SIZE>,
+    // a key went up or did not fit, the keys held are no chord to repeat
+    released: bool,
+    // the keys held were looked up in the rules, they do not repeat
+    checked: bool,
+    // the repeating key, and what it resolved to: emit, identity and first key
+    key: Option<Key>,
+    emit: Option<(Emit<Keyb>, Emit<Keyb>, Key)>,
+    sent: Millis,
+    // keys that went out while held, they are left out of their chord
+    swallow: u128,
+    // root held as context for a repeated key, its lone tap is dropped
+    root: Option<Key>,
+    last_event: Millis,
+}
+
+impl AutoRepeat {
+    pub fn new(delay: Millis, rate: Millis) -> Self {
+        AutoRepeat {
+            delay,
+            rate,
+            held: Vec::new(),
+            released: false,
+            checked: false,
+            key: None,
+            emit: None,
+            sent: 0,
+            swallow: 0,
+            root: None,
+            last_event: 0,
+        }
+    }
+
+    fn send<const R: usize>(&self, out: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+        let Some((emit, identity, first)) = self.emit else {
+            return Ok(());
+        };
+        append(out, |out| {
+            build_keyboard_report(emit, identity, &first, out)
+        })
+    }
+}
+
+impl Stage for AutoRepeat {
+    fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, now: Millis) {
+        for event in events.iter() {
+            self.last_event = now;
+            self.checked = false;
+            match *event {
+                Event::Down(key) => {
+                    // another key stops the repeat, the held one stays swallowed
+                    if self.key.is_some_and(|repeating| repeating != key) {
+                        self.key = None;
+                    }
+                    self.released |= self.held.push(Pressed(key)).is_err();
+                }
+                Event::Up(key) => {
+                    if self.key == Some(key) {
+                        self.key = None;
+                    }
+                    self.held.retain(|Pressed(held)| *held != key);
+                    self.released = !self.held.is_empty();
+                }
+            }
+        }
+    }
+
+    fn chord<const N: usize>(&mut self, chord: &mut Vec<Pressed, N>) {
+        let gone = chord
+            .iter()
+            .fold(0, |gone, Pressed(key)| gone | (self.swallow & bit(*key)));
+        if gone == 0 {
+            return;
+        }
+        chord.retain(|Pressed(key)| gone & bit(*key) == 0);
+        self.swallow &= !gone;
+        if let Some(root) = self.root.take() {
+            if chord.as_slice() == [Pressed(root)] {
+                chord.clear();
+            }
+        }
+    }
+
+    // The keys held are looked up once they sat still for the delay, not on
+    // every tick after
+    fn tick<const R: usize>(
+        &mut self,
+        now: Millis,
+        rules: &Rules<Keyb>,
+        out: &mut Vec<Keyb, R>,
+    ) -> Result<(), Overflow> {
+        if self.key.is_some() {
+            if now.wrapping_sub(self.sent) >= self.rate {
+                self.sent = now;
+                return self.send(out);
+            }
+            return Ok(());
+        }
+        if self.held.is_empty()
+            || self.released
+            || self.checked
+            || now.wrapping_sub(self.last_event) < self.delay
+        {
+            return Ok(());
+        }
+        self.checked = true;
+        let chord = &self.held;
+        // a swallowed key still held is no new chord
+        if chord
+            .iter()
+            .any(|Pressed(key)| self.swallow & bit(*key) != 0)
+        {
+            return Ok(());
+        }
+        let context = rules.find(chord).and_then(|rule| rule.context(chord));
+        if !rules.settled(chord) && context != Some(chord.len() - 1) {
+            return Ok(());
+        }
+        let (Some(Pressed(first)), Some(last)) = (chord.first(), chord.last()) else {
+            return Ok(());
+        };
+        let emit = rules.parse(chord);
+        let identity = if chord.len() > 1 {
+            rules.parse(&[*last])
+        } else {
+            emit
+        };
+        let mut report: Vec<Keyb, R> = Vec::new();
+        let built = build_keyboard_report(emit, identity, first, &mut report);
+        if built.is_err()
+            || report
+                .iter()
+                .all(|code| *code == Keyb::Out || is_modifier(*code))
+        {
+            return Ok(());
+        }
+        let Pressed(key) = *last;
+        self.swallow |= bit(key);
+        self.root = (chord.len() > 1).then_some(*first);
+        self.key = Some(key);
+        self.emit = Some((emit, identity, *first));
+        self.sent = now;
+        self.send(out)
+    }
+
+    fn clear(&mut self) {
+        self.held.clear();
+        self.released = false;
+        self.checked = false;
+        self.key = None;
+        self.swallow = 0;
+        self.root = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AutoRepeat;
+    use crate::{
+        alias,
+        engine::Engine,
+        lex::{qwerty::*, Event::*, Millis},
+        parse::{
+            ChordEmit,
+            ChordEvent::{self, *},
+            Emit::*,
+            Override, Rules,
+        },
+    };
+    use usbd_human_interface_device::page::Keyboard as Keyb;
+
+    alias!(SPC, Right, K16);
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const J_EVENTS: [ChordEvent; 1] = [On(J)];
+    const Z_EVENTS: [ChordEvent; 1] = [On(Z)];
+    const SPC_EVENTS: [ChordEvent; 1] = [On(SPC)];
+    const LEFT_EVENTS: [ChordEvent; 2] = [On(SPC), On(J)];
+    const SHIFT_EVENTS: [ChordEvent; 2] = [On(D), Any];
+    const RULES: [ChordEmit<Keyb>; 6] = [
+        ChordEmit::new(&LEFT_EVENTS, Code(Keyb::LeftArrow)),
+        ChordEmit::new(&SPC_EVENTS, Code(Keyb::Space)),
+        ChordEmit::new(&SHIFT_EVENTS, Shift(&Identity)),
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&J_EVENTS, Code(Keyb::J)),
+        ChordEmit::new(&Z_EVENTS, Code(Keyb::LeftShift)),
+    ];
+
+    type Repeating = Engine<'static, 16, 64, AutoRepeat>;
+
+    fn engine() -> Repeating {
+        Engine::new(Rules::ordered(&RULES)).pipeline(AutoRepeat::new(300, 50))
+    }
+
+    // Runs the virtual clock in 10ms steps up to end
+    fn run(engine: &mut Repeating, end: Millis) {
+        for now in (engine.now()..=end).step_by(10) {
+            engine.tick(now);
+        }
+    }
+
+    fn count(reports: &[Keyb], code: Keyb) -> usize {
+        reports.iter().filter(|c| **c == code).count()
+    }
+
+    #[test]
+    fn held_key_repeats() {
+        let mut engine = engine();
+        engine.feed(Down(Q.into()));
+        run(&mut engine, 290);
+        assert!(engine.drain_reports().is_empty());
+        // sent at 300, then every 50ms
+        run(&mut engine, 450);
+        let reports = engine.drain_reports();
+        assert_eq!(4, count(&reports, Keyb::Q));
+        // the release sends nothing more
+        engine.feed(Up(Q.into()));
+        run(&mut engine, 1000);
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn quick_tap() {
+        let mut engine = engine();
+        engine.feed(Down(Q.into()));
+        run(&mut engine, 100);
+        engine.feed(Up(Q.into()));
+        run(&mut engine, 1000);
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn under_held_keys() {
+        let mut engine = engine();
+        engine.feed(Down(SPC.into()));
+        engine.feed(Down(J.into()));
+        run(&mut engine, 400);
+        let reports = engine.drain_reports();
+        assert_eq!(3, count(&reports, Keyb::LeftArrow));
+        // the layer key went out with the repeat, its release is no Space
+        engine.feed(Up(J.into()));
+        engine.feed(Up(SPC.into()));
+        assert!(engine.drain_reports().is_empty());
+        engine.tick(1000);
+        engine.feed(Down(SPC.into()));
+        engine.feed(Up(SPC.into()));
+        assert_eq!(&[Keyb::Space], engine.drain_reports().as_slice());
+
+        // modifiers stay with every repeat
+        engine.feed(Down(D.into()));
+        engine.feed(Down(J.into()));
+        run(&mut engine, 1350);
+        assert_eq!(
+            &[
+                Keyb::LeftShift,
+                Keyb::J,
+                Keyb::Out,
+                Keyb::LeftShift,
+                Keyb::J
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn not_for_modifiers() {
+        let mut engine = engine();
+        engine.feed(Down(Z.into()));
+        run(&mut engine, 1000);
+        assert!(engine.drain_reports().is_empty());
+        // a layer key alone may still become a chord
+        engine.feed(Down(SPC.into()));
+        run(&mut engine, 2000);
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn stops_for_another_key() {
+        let mut engine = engine();
+        engine.feed(Down(Q.into()));
+        run(&mut engine, 360);
+        assert_eq!(2, count(&engine.drain_reports(), Keyb::Q));
+        engine.feed(Down(J.into()));
+        engine.feed(Up(J.into()));
+        run(&mut engine, 600);
+        engine.feed(Up(Q.into()));
+        // J goes out on its own, Q never again
+        assert_eq!(&[Keyb::J], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn repeats_the_emit() {
+        // the override turns every repeat of Shift+J into 1
+        const OVERRIDES: [Override<Keyb>; 1] = [Override::new(
+            &[Keyb::LeftShift],
+            Keyb::J,
+            Code(Keyb::Keyboard1),
+        )];
+        let rules = Rules::ordered(&RULES).overrides(&OVERRIDES);
+        let mut engine: Repeating = Engine::new(rules).pipeline(AutoRepeat::new(300, 50));
+        engine.feed(Down(D.into()));
+        engine.feed(Down(J.into()));
+        run(&mut engine, 360);
+        assert_eq!(
+            &[Keyb::Keyboard1, Keyb::Out, Keyb::Keyboard1],
+            engine.drain_reports().as_slice()
+        );
+    }
+}
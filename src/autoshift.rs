@@ -0,0 +1,287 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    lex::{bit, index, Event, Millis, Pressed},
+    parse::Emit,
+    pipeline::Stage,
+    report::{is_modifier, Overflow},
+};
+
+// A chord whose last key was held past the threshold comes out shifted. Keys
+// in skip never shift, e.g. home row mods, keys in shifted send their own emit
+pub struct AutoShift {
+    threshold: Millis,
+    skip: &'static [Pressed],
+    shifted: &'static [(Pressed, Emit<Keyb>)],
+    down: [Millis; 128],
+    // released past the threshold, until their chord resolves
+    long: u128,
+    // the emit last resolved shifts, until its report is built
+    shift: bool,
+}
+
+impl AutoShift {
+    pub const fn new(threshold: Millis) -> Self {
+        AutoShift {
+            threshold,
+            skip: &[],
+            shifted: &[],
+            down: [0; 128],
+            long: 0,
+            shift: false,
+        }
+    }
+
+    pub const fn skip(self, keys: &'static [Pressed]) -> Self {
+        AutoShift { skip: keys, ..self }
+    }
+
+    pub const fn shifted(self, table: &'static [(Pressed, Emit<Keyb>)]) -> Self {
+        AutoShift {
+            shifted: table,
+            ..self
+        }
+    }
+}
+
+impl Stage for AutoShift {
+    fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, now: Millis) {
+        for event in events.iter() {
+            match *event {
+                Event::Down(key) => {
+                    self.down[index(key)] = now;
+                    self.long &= !bit(key);
+                }
+                Event::Up(key) => {
+                    if now.wrapping_sub(self.down[index(key)]) >= self.threshold {
+                        self.long |= bit(key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, chord: &[Pressed], emit: Emit<Keyb>) -> Emit<Keyb> {
+        let (emit, shift) = self.resolve(chord, emit);
+        self.shift = shift;
+        emit
+    }
+
+    // The report of an emit held long gets a shift as it is built, one that
+    // does not fit stays as it is
+    fn built<const R: usize>(
+        &mut self,
+        report: &mut Vec<Keyb, R>,
+        start: usize,
+    ) -> Result<(), Overflow> {
+        let codes = &report[start..];
+        // Shift(&Identity) rules shift already
+        if !core::mem::take(&mut self.shift)
+            || codes.is_empty()
+            || codes.contains(&Keyb::LeftShift)
+            || codes.contains(&Keyb::RightShift)
+        {
+            return Ok(());
+        }
+        report.insert(start, Keyb::LeftShift).map_err(|_| Overflow)
+    }
+
+    fn clear(&mut self) {
+        self.long = 0;
+        self.shift = false;
+    }
+}
+
+impl AutoShift {
+    // The emit to send for a chord, and whether it comes out shifted. Modifier
+    // emits such as Ctrl(&Identity) and behaviors never shift
+    fn resolve(&mut self, chord: &[Pressed], emit: Emit<Keyb>) -> (Emit<Keyb>, bool) {
+        let Some(last) = chord.last() else {
+            return (emit, false);
+        };
+        if self.long & bit(last.0) == 0 || self.skip.contains(last) {
+            return (emit, false);
+        }
+        self.long &= !bit(last.0);
+        // overrides are for the key alone, under held keys it shifts as usual
+        if let Some((_, shifted)) = self.shifted.iter().find(|(key, _)| chord == [*key]) {
+            return (*shifted, false);
+        }
+        let shift = match emit {
+            Emit::Code(code) => !is_modifier(code),
+            Emit::Identity => true,
+            _ => false,
+        };
+        (emit, shift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alias,
+        engine::Engine,
+        leader::{Leader, Node},
+        lex::{qwerty::*, Event::*},
+        parse::{
+            ChordEmit,
+            ChordEvent::{self, *},
+            Emit::*,
+            Rules,
+        },
+    };
+
+    alias!(SPC, Right, K16);
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const J_EVENTS: [ChordEvent; 1] = [On(J)];
+    const D_EVENTS: [ChordEvent; 1] = [On(D)];
+    const COMMA_EVENTS: [ChordEvent; 1] = [On(COMMA)];
+    const Z_EVENTS: [ChordEvent; 1] = [On(Z)];
+    const SHIFT_EVENTS: [ChordEvent; 2] = [On(D), Any];
+    const CTRL_EVENTS: [ChordEvent; 2] = [On(S), Any];
+    const ONE_EVENTS: [ChordEvent; 2] = [On(SPC), On(Q)];
+    const TWO_EVENTS: [ChordEvent; 2] = [On(SPC), On(W)];
+    const RULES: [ChordEmit<Keyb>; 9] = [
+        ChordEmit::new(&ONE_EVENTS, Code(Keyb::Keyboard1)),
+        ChordEmit::new(&TWO_EVENTS, Code(Keyb::Keyboard2)),
+        ChordEmit::new(&SHIFT_EVENTS, Shift(&Identity)),
+        ChordEmit::new(&CTRL_EVENTS, Ctrl(&Identity)),
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&J_EVENTS, Code(Keyb::J)),
+        ChordEmit::new(&D_EVENTS, Code(Keyb::D)),
+        ChordEmit::new(&COMMA_EVENTS, Code(Keyb::Comma)),
+        ChordEmit::new(&Z_EVENTS, Code(Keyb::LeftControl)),
+    ];
+
+    fn engine() -> Engine<'static, 16, 16, AutoShift> {
+        Engine::new(Rules::ordered(&RULES)).pipeline(
+            AutoShift::new(200)
+                .skip(&[D, S])
+                .shifted(&[(COMMA, Code(Keyb::Semicolon))]),
+        )
+    }
+
+    // Keys go down in order at now and come up in reverse after hold
+    fn press(engine: &mut Engine<'static, 16, 16, AutoShift>, keys: &[Pressed], hold: Millis) {
+        let now = engine.now();
+        for key in keys {
+            engine.feed(Down(key.0));
+        }
+        engine.tick(now + hold);
+        for key in keys.iter().rev() {
+            engine.feed(Up(key.0));
+        }
+        engine.tick(now + hold + 100);
+    }
+
+    #[test]
+    fn threshold() {
+        let mut engine = engine();
+        press(&mut engine, &[Q], 100);
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+        press(&mut engine, &[Q], 200);
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn under_held_keys() {
+        let mut engine = engine();
+        press(&mut engine, &[SPC, Q], 300);
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Keyboard1],
+            engine.drain_reports().as_slice()
+        );
+        // shifted once, whether by the rule or the hold
+        press(&mut engine, &[D, J], 300);
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::J],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn skip_and_override() {
+        let mut engine = engine();
+        press(&mut engine, &[D], 300);
+        assert_eq!(&[Keyb::D], engine.drain_reports().as_slice());
+        press(&mut engine, &[COMMA], 300);
+        assert_eq!(&[Keyb::Semicolon], engine.drain_reports().as_slice());
+        press(&mut engine, &[COMMA], 100);
+        assert_eq!(&[Keyb::Comma], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn only_the_long_tap() {
+        let mut engine = engine();
+        engine.feed(Down(SPC.0));
+        engine.feed(Down(Q.0));
+        engine.feed(Up(Q.0));
+        let now = engine.now();
+        engine.feed(Down(W.0));
+        engine.tick(now + 300);
+        engine.feed(Up(W.0));
+        engine.feed(Up(SPC.0));
+        assert_eq!(
+            &[Keyb::Keyboard1, Keyb::Out, Keyb::LeftShift, Keyb::Keyboard2],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn not_for_modifiers() {
+        let mut engine = engine();
+        press(&mut engine, &[S, J], 300);
+        assert_eq!(
+            &[Keyb::LeftControl, Keyb::J],
+            engine.drain_reports().as_slice()
+        );
+        press(&mut engine, &[Z], 300);
+        assert_eq!(&[Keyb::LeftControl], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn full_report() {
+        let mut report: Vec<Keyb, 4> = Vec::from_slice(&[Keyb::Q, Keyb::Out, Keyb::W]).unwrap();
+        let mut stage = AutoShift::new(200);
+        stage.shift = true;
+        assert_eq!(Ok(()), stage.built(&mut report, 0));
+        // the shift fills the report, the next has no room
+        stage.shift = true;
+        assert_eq!(Err(Overflow), stage.built(&mut report, 3));
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Q, Keyb::Out, Keyb::W],
+            report.as_slice()
+        );
+    }
+
+    #[test]
+    fn after_a_sequence() {
+        alias!(LEAD, Left, K17);
+        const TABLE: [Node; 1] = [Node(
+            &[G],
+            Some(Code(Keyb::F1)),
+            &[Node(&[J], Some(Code(Keyb::F2)), &[])],
+        )];
+        let mut engine: Engine<'static, 16, 16, (Leader, AutoShift)> =
+            Engine::new(Rules::ordered(&RULES))
+                .pipeline((Leader::new(&[LEAD], &TABLE, 1000), AutoShift::new(200)));
+        for key in [LEAD, G] {
+            engine.feed(Down(key.0));
+            engine.feed(Up(key.0));
+        }
+        // Q ends the sequence, it goes out after it and only Q is shifted
+        engine.feed(Down(Q.0));
+        engine.tick(300);
+        engine.feed(Up(Q.0));
+        assert_eq!(
+            &[Keyb::F1, Keyb::Out, Keyb::LeftShift, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+}
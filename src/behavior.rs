@@ -0,0 +1,326 @@
+use core::fmt;
+
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    lex::{bit, Key, Millis, Pressed},
+    parse::Emit,
+    report::{append, build_keyboard_report, Overflow},
+};
+
+// A user defined action, bound to a chord with Emit::Custom. Behaviors are
+// shared statics, any state of their own needs interior mutability (atomics)
+pub trait Behavior: Sync {
+    // The chord bound to the behavior resolved. A chord no further key can
+    // change resolves on press, any other on release
+    fn press(&self, ctx: &mut Context);
+
+    // The keys of the chord are released, right after press if it resolved
+    // on release
+    fn release(&self, _ctx: &mut Context) {}
+
+    // Every tick after release, the behavior stays active while this returns true
+    fn tick(&self, _ctx: &mut Context) -> bool {
+        false
+    }
+}
+
+// Behaviors are told apart by address
+impl PartialEq for dyn Behavior {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::addr_eq(self, other)
+    }
+}
+
+impl Eq for dyn Behavior {}
+
+impl fmt::Debug for dyn Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Behavior({:p})", self)
+    }
+}
+
+// What a behavior sees of the engine, and where its reports go
+pub struct Context<'c> {
+    pub now: Millis,
+    // the chord that triggered the behavior, empty on tick and on a release
+    // that follows the keys
+    pub chord: &'c [Pressed],
+    held: u128,
+    reports: &'c mut dyn Reports,
+    // reports were dropped for want of room
+    overflow: bool,
+}
+
+impl<'c> Context<'c> {
+    pub(crate) fn new<const R: usize>(
+        now: Millis,
+        chord: &'c [Pressed],
+        held: u128,
+        reports: &'c mut Vec<Keyb, R>,
+    ) -> Self {
+        Context {
+            now,
+            chord,
+            held,
+            reports,
+            overflow: false,
+        }
+    }
+
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held & bit(key) != 0
+    }
+
+    // Sends one report for the emit, modifiers take the side of first. A
+    // report that does not fit is dropped, see Engine::overflowed
+    pub fn emit(&mut self, emit: Emit<Keyb>, first: Key) {
+        self.overflow |= self.reports.emit(emit, first).is_err();
+    }
+
+    // Sends one report with the given codes
+    pub fn report(&mut self, codes: &[Keyb]) {
+        self.overflow |= self.reports.codes(codes).is_err();
+    }
+
+    pub(crate) fn overflowed(&self) -> bool {
+        self.overflow
+    }
+}
+
+// Report buffers of any capacity, so Behavior takes no const generics
+trait Reports {
+    fn emit(&mut self, emit: Emit<Keyb>, first: Key) -> Result<(), Overflow>;
+
+    fn codes(&mut self, codes: &[Keyb]) -> Result<(), Overflow>;
+}
+
+impl<const R: usize> Reports for Vec<Keyb, R> {
+    fn emit(&mut self, emit: Emit<Keyb>, first: Key) -> Result<(), Overflow> {
+        append(self, |reports| {
+            build_keyboard_report(emit, emit, &first, reports)
+        })
+    }
+
+    fn codes(&mut self, codes: &[Keyb]) -> Result<(), Overflow> {
+        append(self, |reports| {
+            reports.extend_from_slice(codes).map_err(|_| Overflow)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::{
+        engine::Engine,
+        lex::{qwerty::*, Event::*},
+        parse::{
+            ChordEmit,
+            ChordEvent::{self, *},
+            Emit::*,
+            Rules,
+        },
+    };
+
+    // Types a fixed sequence, one report per code
+    struct Macro(&'static [Keyb]);
+
+    impl Behavior for Macro {
+        fn press(&self, ctx: &mut Context) {
+            for code in self.0 {
+                ctx.report(&[*code]);
+            }
+        }
+    }
+
+    // Counts its presses, like a bootloader jump that needs a few
+    struct Count(AtomicU32);
+
+    impl Behavior for Count {
+        fn press(&self, _ctx: &mut Context) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Answers late, once a tick comes 100ms after the press
+    struct Late(AtomicU32);
+
+    impl Behavior for Late {
+        fn press(&self, ctx: &mut Context) {
+            self.0.store(ctx.now, Ordering::Relaxed);
+        }
+
+        fn tick(&self, ctx: &mut Context) -> bool {
+            if ctx.now < self.0.load(Ordering::Relaxed) + 100 {
+                return true;
+            }
+            let code = if ctx.is_held(Q.into()) {
+                Keyb::Q
+            } else {
+                Keyb::W
+            };
+            ctx.report(&[code]);
+            false
+        }
+    }
+
+    // Reports A on press while its key is held, B on release
+    struct Hold;
+
+    impl Behavior for Hold {
+        fn press(&self, ctx: &mut Context) {
+            if ctx.chord.iter().all(|Pressed(key)| ctx.is_held(*key)) {
+                ctx.report(&[Keyb::A]);
+            }
+        }
+
+        fn release(&self, ctx: &mut Context) {
+            ctx.report(&[Keyb::B]);
+        }
+    }
+
+    static HI: Macro = Macro(&[Keyb::H, Keyb::I]);
+    static COUNT: Count = Count(AtomicU32::new(0));
+    static LATE: Late = Late(AtomicU32::new(0));
+
+    const E_EVENTS: [ChordEvent; 1] = [On(E)];
+    const R_EVENTS: [ChordEvent; 1] = [On(R)];
+    const T_EVENTS: [ChordEvent; 1] = [On(T)];
+    const RULES: [ChordEmit<Keyb>; 3] = [
+        ChordEmit::new(&E_EVENTS, Custom(&HI)),
+        ChordEmit::new(&R_EVENTS, Custom(&COUNT)),
+        ChordEmit::new(&T_EVENTS, Custom(&LATE)),
+    ];
+
+    fn tap<const R: usize>(engine: &mut Engine<16, R>, key: Pressed) {
+        engine.feed(Down(key.into()));
+        engine.feed(Up(key.into()));
+    }
+
+    #[test]
+    fn press() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        tap(&mut engine, E);
+        assert_eq!(
+            &[Keyb::H, Keyb::Out, Keyb::I],
+            engine.drain_reports().as_slice()
+        );
+
+        tap(&mut engine, R);
+        tap(&mut engine, R);
+        assert_eq!(2, COUNT.0.load(Ordering::Relaxed));
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn tick() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        engine.tick(1000);
+        tap(&mut engine, T);
+        engine.tick(1050);
+        assert!(engine.drain_reports().is_empty());
+        engine.feed(Down(Q.into()));
+        engine.tick(1100);
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+        engine.tick(1200);
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn told_apart_by_address() {
+        assert_eq!(Custom::<Keyb>(&HI), Custom(&HI));
+        assert_ne!(Custom::<Keyb>(&HI), Custom(&COUNT));
+    }
+
+    #[test]
+    fn held_between_press_and_release() {
+        static HOLD: Hold = Hold;
+        const Y_EVENTS: [ChordEvent; 1] = [On(Y)];
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Y_EVENTS, Custom(&HOLD))];
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        engine.feed(Down(Y.into()));
+        assert_eq!(&[Keyb::A], engine.drain_reports().as_slice());
+        engine.feed(Up(Y.into()));
+        assert_eq!(&[Keyb::B], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn under_watchdog() {
+        static HOLD: Hold = Hold;
+        const Y_EVENTS: [ChordEvent; 1] = [On(Y)];
+        const W_EVENTS: [ChordEvent; 1] = [On(W)];
+        const RULES: [ChordEmit<Keyb>; 2] = [
+            ChordEmit::new(&Y_EVENTS, Custom(&HOLD)),
+            ChordEmit::new(&W_EVENTS, Code(Keyb::W)),
+        ];
+        let mut engine: Engine<16, 16> = Engine::new(Rules::ordered(&RULES)).watchdog(100);
+        // Y went up, pressed again it is no orphan of a lost Up
+        tap(&mut engine, Y);
+        engine.tick(50);
+        tap(&mut engine, Y);
+        // nor a stuck key to release under W
+        engine.feed(Down(W.into()));
+        engine.tick(120);
+        engine.feed(Up(W.into()));
+        assert_eq!(
+            &[
+                Keyb::A,
+                Keyb::Out,
+                Keyb::B,
+                Keyb::Out,
+                Keyb::A,
+                Keyb::Out,
+                Keyb::B,
+                Keyb::Out,
+                Keyb::W
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn long_macro() {
+        static LONG: Macro = Macro(&[Keyb::A; 20]);
+        const Y_EVENTS: [ChordEvent; 1] = [On(Y)];
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Y_EVENTS, Custom(&LONG))];
+        let mut engine: Engine<16, 64> = Engine::new(Rules::ordered(&RULES));
+        tap(&mut engine, Y);
+        // one chord makes REPORT_SIZE codes at most
+        let reports = engine.drain_reports();
+        assert_eq!(16, reports.iter().filter(|c| **c == Keyb::A).count());
+        assert!(engine.overflowed());
+    }
+
+    #[test]
+    fn under_modifiers() {
+        static NESTED: Count = Count(AtomicU32::new(0));
+        const Y_EVENTS: [ChordEvent; 1] = [On(Y)];
+        const CUSTOM: Emit<Keyb> = Custom(&NESTED);
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Y_EVENTS, Shift(&CUSTOM))];
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        tap(&mut engine, Y);
+        assert_eq!(1, NESTED.0.load(Ordering::Relaxed));
+        // no lone shift
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn too_many_at_once() {
+        static MANY: Count = Count(AtomicU32::new(0));
+        const Y_U_EVENTS: [ChordEvent; 2] = [On(Y), On(U)];
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Y_U_EVENTS, Custom(&MANY))];
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        // every tap under Y is a press of its own
+        engine.feed(Down(Y.into()));
+        for _ in 0..5 {
+            tap(&mut engine, U);
+        }
+        engine.feed(Up(Y.into()));
+        assert_eq!(4, MANY.0.load(Ordering::Relaxed));
+        assert!(engine.overflowed());
+    }
+}
@@ -1,5 +1,7 @@
 use tastlib::alias;
 use tastlib::parse::ChordEvent::*;
+use tastlib::parse::{Emit::Code, Override};
+use usbd_human_interface_device::page::Keyboard as Keyb;
 
 alias!(TAB, Left, K16);
 alias!(BCK, Left, K17);
@@ -17,6 +19,27 @@ alias!(R_A, Right, K7);
 alias!(R_S, Right, K8);
 alias!(R_C, Right, K9);
 
+// Shift+Backspace deletes forward
+pub const OVERRIDES: [Override<Keyb>; 1] = [Override::new(
+    &[Keyb::LeftShift],
+    Keyb::DeleteBackspace,
+    Code(Keyb::DeleteForward),
+)];
+
+// Counterparts for the alternate repeat key
+pub const ALTERNATES: [(&[Keyb], &[Keyb]); 3] = [
+    (&[Keyb::UpArrow], &[Keyb::DownArrow]),
+    (&[Keyb::LeftControl, Keyb::Z], &[Keyb::LeftControl, Keyb::Y]),
+    (
+        &[Keyb::LeftShift, Keyb::Keyboard0],
+        &[Keyb::LeftShift, Keyb::Keyboard9],
+    ),
+];
+
+// Held on purpose, so never auto shifted
+pub const NO_AUTO_SHIFT: [tastlib::lex::Pressed; 12] =
+    [L_G, L_A, L_S, L_C, R_G, R_A, R_S, R_C, TAB, BCK, RET, SPC];
+
 #[rustfmt::skip]
 mod unformatted {
     use tastlib::{chord, parse::ChordEmit};
@@ -26,29 +49,31 @@ mod unformatted {
     chord!( R_ALT,          2, [On(R_A), LAny],                     Alt(&Identity));
     chord!( R_SHIFT,        2, [On(R_S), LAny],                     Shift(&Identity));
     chord!( R_CTRL,         2, [On(R_C), LAny],                     Ctrl(&Identity));
-    chord!( R_GUI_ALT,      2, [Both(R_G, R_A), LAny],              Mod(&Alt(&Identity)));
-    chord!( R_GUI_SHIFT,    2, [Both(R_G, R_S), LAny],              Mod(&Shift(&Identity)));
-    chord!( R_GUI_CTRL,     2, [Both(R_G, R_C), LAny],              Mod(&Ctrl(&Identity)));
-    chord!( R_ALT_SHIFT,    2, [Both(R_A, R_S), LAny],              Alt(&Shift(&Identity)));
-    chord!( R_CTRL_ALT,     2, [Both(R_C, R_A), LAny],              Ctrl(&Alt(&Identity)));
-    chord!( R_CTRL_SHIFT,   2, [Both(R_C, R_S), LAny],              Ctrl(&Shift(&Identity)));
-    chord!( R_ALLMOD,       4, [On(R_A), On(R_S), On(R_C), LAny],   Ctrl(&Alt(&Shift(&Identity))));
+    chord!( R_GUI_ALT,      2, [All(&[R_G, R_A]), LAny],            Mod(&Alt(&Identity)));
+    chord!( R_GUI_SHIFT,    2, [All(&[R_G, R_S]), LAny],            Mod(&Shift(&Identity)));
+    chord!( R_GUI_CTRL,     2, [All(&[R_G, R_C]), LAny],            Mod(&Ctrl(&Identity)));
+    chord!( R_ALT_SHIFT,    2, [All(&[R_A, R_S]), LAny],            Alt(&Shift(&Identity)));
+    chord!( R_CTRL_ALT,     2, [All(&[R_C, R_A]), LAny],            Ctrl(&Alt(&Identity)));
+    chord!( R_CTRL_SHIFT,   2, [All(&[R_C, R_S]), LAny],            Ctrl(&Shift(&Identity)));
+    chord!( R_ALLMOD,       2, [All(&[R_A, R_S, R_C]), LAny],       Ctrl(&Alt(&Shift(&Identity))));
 
     // Homerow mods left
     chord!( L_GUI,          2, [On(L_G), RAny],                     Mod(&Identity));
     chord!( L_ALT,          2, [On(L_A), RAny],                     Alt(&Identity));
     chord!( L_SHIFT,        2, [On(L_S), RAny],                     Shift(&Identity));
     chord!( L_CTRL,         2, [On(L_C), RAny],                     Ctrl(&Identity));
-    chord!( L_GUI_ALT,      2, [Both(L_G, L_A), RAny],              Mod(&Alt(&Identity)));
-    chord!( L_GUI_SHIFT,    2, [Both(L_G, L_S), RAny],              Mod(&Shift(&Identity)));
-    chord!( L_GUI_CTRL,     2, [Both(L_G, L_C), RAny],              Mod(&Ctrl(&Identity)));
-    chord!( L_ALT_SHIFT,    2, [Both(L_A, L_S), RAny],              Alt(&Shift(&Identity)));
-    chord!( L_CTRL_ALT,     2, [Both(L_C, L_A), RAny],              Ctrl(&Alt(&Identity)));
-    chord!( L_CTRL_SHIFT,   2, [Both(L_C, L_S), RAny],              Ctrl(&Shift(&Identity)));
-    chord!( L_ALLMOD,       4, [On(L_A), On(L_S), On(L_C), RAny],   Ctrl(&Alt(&Shift(&Identity))));
+    chord!( L_GUI_ALT,      2, [All(&[L_G, L_A]), RAny],            Mod(&Alt(&Identity)));
+    chord!( L_GUI_SHIFT,    2, [All(&[L_G, L_S]), RAny],            Mod(&Shift(&Identity)));
+    chord!( L_GUI_CTRL,     2, [All(&[L_G, L_C]), RAny],            Mod(&Ctrl(&Identity)));
+    chord!( L_ALT_SHIFT,    2, [All(&[L_A, L_S]), RAny],            Alt(&Shift(&Identity)));
+    chord!( L_CTRL_ALT,     2, [All(&[L_C, L_A]), RAny],            Ctrl(&Alt(&Identity)));
+    chord!( L_CTRL_SHIFT,   2, [All(&[L_C, L_S]), RAny],            Ctrl(&Shift(&Identity)));
+    chord!( L_ALLMOD,       2, [All(&[L_A, L_S, L_C]), RAny],       Ctrl(&Alt(&Shift(&Identity))));
 
 
-    chord!(TAB_SPC_ESC,   1, [Both(TAB, SPC)], Code(Keyb::Escape));
+    chord!(TAB_SPC_ESC,   1, [All(&[TAB, SPC])], Code(Keyb::Escape));
+    chord!(BCK_RET_REPEAT, 1, [All(&[BCK, RET])], Repeat);
+    chord!(BCK_SPC_ALT,   1, [All(&[BCK, SPC])], AltRepeat);
 
     // Tab layer (shift)
     chord!(TAB_SHIFT,     2, [On(TAB), Any], Shift(&Identity));
@@ -155,7 +180,7 @@ mod unformatted {
     chord!(ON_DOT,             1, [On(DOT)],          Code(Keyb::Dot));
     chord!(ON_FORWARDSLASH,    1, [On(FORWARDSLASH)], Code(Keyb::ForwardSlash));
 
-    pub const RULES: [ChordEmit<Keyboard>; 111] = [
+    pub const RULES: [ChordEmit<Keyboard>; 113] = [
         R_GUI,
         R_ALT,
         R_SHIFT,
@@ -179,6 +204,8 @@ mod unformatted {
         L_CTRL_SHIFT,
         L_ALLMOD,
         TAB_SPC_ESC,
+        BCK_RET_REPEAT,
+        BCK_SPC_ALT,
         TAB_SHIFT,
         BCK_AE,
         BCK_OE,
@@ -0,0 +1,181 @@
+use heapless::Vec;
+
+use crate::lex::{Event, Key, Millis};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    // Report a change at once, then ignore the key until it has been stable for the debounce time
+    Eager,
+    // Report changes once no key at all has changed for the debounce time
+    Deferred,
+    // Report a change once that key alone has been stable for the debounce time
+    PerKey,
+}
+
+pub struct Debouncer<const N: usize> {
+    keys: [Key; N],
+    algorithm: Algorithm,
+    time: Millis,
+    stable: [bool; N],
+    raw: [bool; N],
+    changed: [Option<Millis>; N],
+    last_change: Millis,
+}
+
+impl<const N: usize> Debouncer<N> {
+    pub fn new(keys: [Key; N], algorithm: Algorithm, time: Millis) -> Self {
+        Debouncer {
+            keys,
+            algorithm,
+            time,
+            stable: [false; N],
+            raw: [false; N],
+            changed: [None; N],
+            last_change: 0,
+        }
+    }
+
+    pub fn update(&mut self, samples: &[bool; N], now: Millis) -> Vec<Event, N> {
+        let mut events: Vec<Event, N> = Vec::new();
+        for (ix, sample) in samples.iter().enumerate() {
+            if *sample != self.raw[ix] {
+                self.raw[ix] = *sample;
+                if self.algorithm != Algorithm::Eager {
+                    self.changed[ix] = Some(now);
+                }
+                self.last_change = now;
+            }
+        }
+        for ix in 0..N {
+            if self.raw[ix] == self.stable[ix] {
+                continue;
+            }
+            let since = match self.algorithm {
+                Algorithm::Eager | Algorithm::PerKey => self.changed[ix],
+                Algorithm::Deferred => Some(self.last_change),
+            };
+            if let Some(since) = since {
+                if now.wrapping_sub(since) < self.time {
+                    continue;
+                }
+            }
+            self.stable[ix] = self.raw[ix];
+            if self.algorithm == Algorithm::Eager {
+                // start the lockout
+                self.changed[ix] = Some(now);
+            }
+            let event = if self.stable[ix] {
+                Event::Down(self.keys[ix])
+            } else {
+                Event::Up(self.keys[ix])
+            };
+            events.push(event).unwrap();
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex::{qwerty::*, KeyId};
+    use Event::*;
+
+    const KEYS: [Key; 2] = [Key::Left(KeyId::K1), Key::Left(KeyId::K2)];
+
+    // Q bounces on press and release, W stays up
+    const CHATTER: [(Millis, [bool; 2]); 12] = [
+        (0, [true, false]),
+        (1, [false, false]),
+        (2, [true, false]),
+        (3, [false, false]),
+        (4, [true, false]),
+        (10, [true, false]),
+        (20, [true, false]),
+        (30, [false, false]),
+        (31, [true, false]),
+        (32, [false, false]),
+        (40, [false, false]),
+        (50, [false, false]),
+    ];
+
+    fn run(
+        debouncer: &mut Debouncer<2>,
+        samples: &[(Millis, [bool; 2])],
+    ) -> Vec<(Millis, Event), 8> {
+        let mut out: Vec<(Millis, Event), 8> = Vec::new();
+        for (now, sample) in samples {
+            for event in debouncer.update(sample, *now) {
+                out.push((*now, event)).unwrap();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn eager() {
+        let mut debouncer = Debouncer::new(KEYS, Algorithm::Eager, 5);
+        let events = run(&mut debouncer, &CHATTER);
+        assert_eq!(
+            &[(0, Down(Q.into())), (30, Up(Q.into()))],
+            events.as_slice()
+        );
+    }
+
+    #[test]
+    fn deferred() {
+        let mut debouncer = Debouncer::new(KEYS, Algorithm::Deferred, 5);
+        let events = run(&mut debouncer, &CHATTER);
+        assert_eq!(
+            &[(10, Down(Q.into())), (40, Up(Q.into()))],
+            events.as_slice()
+        );
+    }
+
+    #[test]
+    fn per_key() {
+        let mut debouncer = Debouncer::new(KEYS, Algorithm::PerKey, 5);
+        let events = run(&mut debouncer, &CHATTER);
+        assert_eq!(
+            &[(10, Down(Q.into())), (40, Up(Q.into()))],
+            events.as_slice()
+        );
+    }
+
+    #[test]
+    fn zero_time_passes_through() {
+        for algorithm in [Algorithm::Eager, Algorithm::Deferred, Algorithm::PerKey] {
+            let mut debouncer = Debouncer::new(KEYS, algorithm, 0);
+            let events = run(&mut debouncer, &CHATTER[..3]);
+            assert_eq!(
+                &[(0, Down(Q.into())), (1, Up(Q.into())), (2, Down(Q.into()))],
+                events.as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn deferred_waits_for_all_keys() {
+        // W chatters while Q is held steady
+        let samples = [
+            (0, [true, false]),
+            (4, [true, true]),
+            (8, [true, false]),
+            (12, [true, true]),
+            (20, [true, true]),
+        ];
+        let mut debouncer = Debouncer::new(KEYS, Algorithm::Deferred, 5);
+        let events = run(&mut debouncer, &samples);
+        assert_eq!(
+            &[(20, Down(Q.into())), (20, Down(W.into()))],
+            events.as_slice()
+        );
+
+        let mut debouncer = Debouncer::new(KEYS, Algorithm::PerKey, 5);
+        let events = run(&mut debouncer, &samples);
+        assert_eq!(
+            &[(8, Down(Q.into())), (20, Down(W.into()))],
+            events.as_slice()
+        );
+    }
+}
@@ -0,0 +1,861 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    behavior::{Behavior, Context},
+    lex::{
+        bit, chord_grouped_into, chord_into, stream_into, Completion, Event, Key, Millis, Pressed,
+        Stream, Trigger, PRESS_SIZE, REPORT_SIZE,
+    },
+    parse::{leaf, Emit, Rules},
+    pipeline::Stage,
+    report::{append, eval_chord_staged, override_reports, sideless, Early, Fallback, Overflow},
+    watchdog::{Recovery, Watchdog},
+};
+
+// Owns everything between the switches and the host, reports pile up until
+// drained. PRESS keys make a chord at most and one chord makes REPORT codes at
+// most, see capacities
+pub struct Engine<
+    'a,
+    const STACK: usize,
+    const REPORTS: usize,
+    P: Stage = (),
+    const PRESS: usize = PRESS_SIZE,
+    const REPORT: usize = REPORT_SIZE,
+> {
+    stack: Vec<Event, STACK>,
+    rules: Rules<'a, Keyb>,
+    chords: Chords,
+    fallback: Fallback,
+    early: Option<Early>,
+    watchdog: Option<Watchdog>,
+    on_recovery: fn(Recovery),
+    pipeline: P,
+    // behaviors that still want ticks
+    active: Vec<&'static dyn Behavior, 8>,
+    // behaviors pressed on Down, with their keys not yet released
+    pressed: Vec<(&'static dyn Behavior, u128), 4>,
+    // keys logically down, layer and modifier keys among them
+    held: u128,
+    // keys settled on their own, see Rules::settled_keys
+    settled: u128,
+    // the last report of a chord, for Emit::Repeat and Emit::AltRepeat
+    last: Vec<Keyb, REPORT>,
+    alternates: &'static [(&'static [Keyb], &'static [Keyb])],
+    now: Millis,
+    reports: Vec<Keyb, REPORTS>,
+    // events or reports were dropped for want of room, see overflowed
+    overflow: bool,
+}
+
+impl<'a, const STACK: usize, const REPORTS: usize> Engine<'a, STACK, REPORTS> {
+    pub fn new(rules: Rules<'a, Keyb>) -> Self {
+        Engine {
+            stack: Vec::new(),
+            rules,
+            chords: Chords::Completion(Completion::Root),
+            fallback: Fallback::default(),
+            early: None,
+            watchdog: None,
+            on_recovery: |_| {},
+            pipeline: (),
+            active: Vec::new(),
+            pressed: Vec::new(),
+            held: 0,
+            settled: rules.settled_keys(),
+            last: Vec::new(),
+            alternates: &[],
+            now: 0,
+            reports: Vec::new(),
+            overflow: false,
+        }
+    }
+}
+
+impl<
+        'a,
+        const STACK: usize,
+        const REPORTS: usize,
+        P: Stage,
+        const PRESS: usize,
+        const REPORT: usize,
+    > Engine<'a, STACK, REPORTS, P, PRESS, REPORT>
+{
+    // Recover stuck, orphaned and stray keys, see Watchdog
+    pub fn watchdog(self, max_hold: Millis) -> Self {
+        Engine {
+            watchdog: Some(Watchdog::new(max_hold)),
+            ..self
+        }
+    }
+
+    // Called with every recovery of the watchdog, e.g. for logging
+    pub fn on_recovery(self, on_recovery: fn(Recovery)) -> Self {
+        Engine {
+            on_recovery,
+            ..self
+        }
+    }
+
+    // When a chord is complete, Completion::Root unless given. The last of
+    // completion, grouped and stream wins
+    pub fn completion(self, completion: Completion) -> Self {
+        Engine {
+            chords: Chords::Completion(completion),
+            ..self
+        }
+    }
+
+    // Each group of keys makes chords of its own, see lex::chord_grouped
+    pub fn grouped(self, group: fn(Key) -> usize) -> Self {
+        Engine {
+            chords: Chords::Grouped(group),
+            ..self
+        }
+    }
+
+    // A held root emits together with each inner key, see lex::stream
+    pub fn stream(self, trigger: Trigger) -> Self {
+        Engine {
+            chords: Chords::Stream(Stream::new(trigger)),
+            ..self
+        }
+    }
+
+    // What a chord emits when its keys are not all used, Fallback::Matched
+    // unless given
+    pub fn fallback(self, fallback: Fallback) -> Self {
+        Engine { fallback, ..self }
+    }
+
+    // A root that no further key can change is emitted on press, see
+    // report::Early. Only with a fallback that keeps the rule found
+    pub fn early(self) -> Self {
+        Engine {
+            early: Some(Early::new(&self.rules)),
+            ..self
+        }
+    }
+
+    // Pairs of reports that stand for each other with Emit::AltRepeat, both ways
+    pub fn alternates(self, alternates: &'static [(&'static [Keyb], &'static [Keyb])]) -> Self {
+        Engine { alternates, ..self }
+    }
+
+    // Stages run in tuple order, e.g. (debounce, (layers, logging))
+    pub fn pipeline<Q: Stage>(self, pipeline: Q) -> Engine<'a, STACK, REPORTS, Q, PRESS, REPORT> {
+        Engine {
+            stack: self.stack,
+            rules: self.rules,
+            chords: self.chords,
+            fallback: self.fallback,
+            early: self.early,
+            watchdog: self.watchdog,
+            on_recovery: self.on_recovery,
+            pipeline,
+            active: self.active,
+            pressed: self.pressed,
+            held: self.held,
+            settled: self.settled,
+            last: self.last,
+            alternates: self.alternates,
+            now: self.now,
+            reports: self.reports,
+            overflow: self.overflow,
+        }
+    }
+
+    // Keys of one chord and codes of the reports of one chord, PRESS_SIZE and
+    // REPORT_SIZE unless given
+    pub fn capacities<const N: usize, const R: usize>(self) -> Engine<'a, STACK, REPORTS, P, N, R> {
+        Engine {
+            stack: self.stack,
+            rules: self.rules,
+            chords: self.chords,
+            fallback: self.fallback,
+            early: self.early,
+            watchdog: self.watchdog,
+            on_recovery: self.on_recovery,
+            pipeline: self.pipeline,
+            active: self.active,
+            pressed: self.pressed,
+            held: self.held,
+            settled: self.settled,
+            last: Vec::new(),
+            alternates: self.alternates,
+            now: self.now,
+            reports: self.reports,
+            overflow: self.overflow,
+        }
+    }
+
+    pub fn stages(&mut self) -> &mut P {
+        &mut self.pipeline
+    }
+
+    pub fn feed(&mut self, event: Event) {
+        let mut events: Vec<Event, PRESS> = Vec::new();
+        if events.push(event).is_err() {
+            self.overflow = true;
+        }
+        self.pipeline.events(&mut events, self.now);
+        for event in events {
+            self.push(event);
+        }
+        self.press_settled();
+        self.eval();
+    }
+
+    pub fn tick(&mut self, now: Millis) {
+        self.now = now;
+        let mut events: Vec<Event, PRESS> = Vec::new();
+        self.pipeline.events(&mut events, now);
+        // the stack is as the last feed left it without events
+        let pushed = !events.is_empty();
+        for event in events {
+            self.push(event);
+        }
+        if pushed {
+            self.press_settled();
+        }
+        let mut stuck: Vec<Key, PRESS_SIZE> = Vec::new();
+        if let Some(watchdog) = &mut self.watchdog {
+            let on_recovery = self.on_recovery;
+            watchdog.tick(&mut self.stack, now, &mut |recovery| {
+                if let Recovery::Stuck(key) = recovery {
+                    stuck.push(key).ok();
+                }
+                on_recovery(recovery);
+            });
+        }
+        for key in stuck {
+            self.held &= !bit(key);
+        }
+        let mut keyboard: Vec<Keyb, REPORT> = Vec::new();
+        let mut active = core::mem::take(&mut self.active);
+        active.retain(|behavior| {
+            let mut ctx = Context::new(now, &[], self.held, &mut keyboard);
+            let keep = behavior.tick(&mut ctx);
+            self.overflow |= ctx.overflowed();
+            keep
+        });
+        self.active = active;
+        if self.pipeline.tick(now, &self.rules, &mut keyboard).is_err() {
+            self.overflow = true;
+        }
+        if !keyboard.is_empty() {
+            self.send(&mut keyboard, false);
+        }
+        self.eval();
+    }
+
+    // Reports since the last drain, separated by Keyb::Out
+    pub fn drain_reports(&mut self) -> Vec<Keyb, REPORTS> {
+        core::mem::take(&mut self.reports)
+    }
+
+    // Whether events or reports were dropped since the last call, a full
+    // stack drops the event, full reports drop whole reports
+    pub fn overflowed(&mut self) -> bool {
+        core::mem::take(&mut self.overflow)
+    }
+
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held & bit(key) != 0
+    }
+
+    // The held root of the pending chord, the layer or modifier key the next
+    // taps are read under
+    pub fn layer(&self) -> Option<Key> {
+        match self.stack.first() {
+            Some(Event::Down(root)) if self.is_held(*root) => Some(*root),
+            _ => None,
+        }
+    }
+
+    pub fn now(&self) -> Millis {
+        self.now
+    }
+
+    // Forget every pending event and held key
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.held = 0;
+        self.pressed.clear();
+        if let Chords::Stream(state) = &mut self.chords {
+            state.clear();
+        }
+        if let Some(early) = &mut self.early {
+            early.emitted = None;
+        }
+        self.pipeline.clear();
+    }
+
+    fn push(&mut self, event: Event) {
+        let mut recovered: Vec<Key, 2> = Vec::new();
+        match event {
+            Event::Down(key) => self.held |= bit(key),
+            Event::Up(key) => self.held &= !bit(key),
+        }
+        if let Event::Up(key) = event {
+            let found = self
+                .pressed
+                .iter()
+                .position(|(_, keys)| keys & bit(key) != 0);
+            if let Some(ix) = found {
+                // its Down left the stack when it was pressed
+                if let Some(watchdog) = &mut self.watchdog {
+                    watchdog.forget(key);
+                }
+                self.pressed[ix].1 &= !bit(key);
+                if self.pressed[ix].1 == 0 {
+                    let (behavior, _) = self.pressed.swap_remove(ix);
+                    self.behave(&[], |ctx| behavior.release(ctx));
+                    self.activate(behavior);
+                }
+                return;
+            }
+        }
+        let on_recovery = self.on_recovery;
+        match &mut self.watchdog {
+            Some(watchdog) => watchdog.feed(&mut self.stack, event, self.now, &mut |recovery| {
+                if let Recovery::Stuck(key) | Recovery::Overflow(key) = recovery {
+                    recovered.push(key).ok();
+                }
+                on_recovery(recovery);
+            }),
+            None => {
+                if self.stack.push(event).is_err() {
+                    self.overflow = true;
+                }
+            }
+        }
+        for key in recovered {
+            self.held &= !bit(key);
+        }
+    }
+
+    fn eval(&mut self) {
+        loop {
+            let mut chrd: Vec<Pressed, PRESS> = Vec::new();
+            let cut = match &mut self.chords {
+                Chords::Completion(completion) => {
+                    chord_into(&mut self.stack, *completion, &mut chrd)
+                }
+                Chords::Grouped(group) => chord_grouped_into(&mut self.stack, *group, &mut chrd),
+                Chords::Stream(state) => stream_into(&mut self.stack, state, &mut chrd),
+            };
+            if cut.is_err() {
+                self.overflow = true;
+            }
+            let Some(Pressed(root)) = chrd.first().copied() else {
+                break;
+            };
+            self.pipeline.chord(&mut chrd);
+            // the root went out on press
+            if let Some(early) = &mut self.early {
+                if early.emitted == Some(root) {
+                    early.emitted = None;
+                    continue;
+                }
+            }
+            self.report(&chrd);
+        }
+        self.press_early();
+    }
+
+    // Reports a chord with the behaviors and repeats it binds
+    fn report(&mut self, chrd: &[Pressed]) {
+        let mut dispatch = Dispatch {
+            pipeline: &mut self.pipeline,
+            found: Vec::new(),
+            repeats: Vec::new(),
+            dropped: false,
+        };
+        let mut keyboard: Vec<Keyb, REPORT> = Vec::new();
+        if eval_chord_staged::<PRESS, _>(
+            chrd,
+            &self.rules,
+            self.fallback,
+            &mut dispatch,
+            &mut keyboard,
+        )
+        .is_err()
+        {
+            self.overflow = true;
+        }
+        let Dispatch {
+            found,
+            repeats,
+            dropped,
+            ..
+        } = dispatch;
+        self.overflow |= dropped;
+        for behavior in found {
+            let mut ctx = Context::new(self.now, chrd, self.held, &mut keyboard);
+            behavior.press(&mut ctx);
+            behavior.release(&mut ctx);
+            self.overflow |= ctx.overflowed();
+            self.activate(behavior);
+        }
+        for repeat in &repeats {
+            let codes = match repeat {
+                Emit::AltRepeat => self.alternate(),
+                _ => self.last.as_slice(),
+            };
+            if codes.is_empty() {
+                continue;
+            }
+            let repeated = append(&mut keyboard, |keyboard| {
+                keyboard.extend_from_slice(codes).map_err(|_| Overflow)
+            });
+            if repeated.is_err() {
+                self.overflow = true;
+            }
+        }
+        self.send(&mut keyboard, !repeats.is_empty());
+    }
+
+    // A lone root no further key can change goes out on press, see early
+    fn press_early(&mut self) {
+        if !self.keeps_rule() {
+            return;
+        }
+        let Some(early) = &mut self.early else {
+            return;
+        };
+        let [Event::Down(root)] = self.stack.as_slice() else {
+            return;
+        };
+        if early.emitted.is_some() || !early.is_settled(*root) {
+            return;
+        }
+        let root = *root;
+        early.emitted = Some(root);
+        self.report(&[Pressed(root)]);
+    }
+
+    // Keys the rule found leaves unused do not change what it emits, so a
+    // settled chord may go out before it is complete
+    fn keeps_rule(&self) -> bool {
+        matches!(self.fallback, Fallback::Matched | Fallback::Drop)
+    }
+
+    // A chord no further key can change that binds a behavior is pressed right
+    // away, it is released once its keys are
+    fn press_settled(&mut self) {
+        if self.stack.is_empty() || self.pressed.is_full() || !self.keeps_rule() {
+            return;
+        }
+        let mut chrd: Vec<Pressed, PRESS> = Vec::new();
+        let mut keys = 0;
+        for event in &self.stack {
+            let Event::Down(key) = event else {
+                return;
+            };
+            if chrd.push(Pressed(*key)).is_err() {
+                return;
+            }
+            keys |= bit(*key);
+        }
+        let settled = match chrd.as_slice() {
+            [Pressed(key)] => self.settled & bit(*key) != 0,
+            _ => self.rules.settled(&chrd),
+        };
+        if !settled {
+            return;
+        }
+        let Emit::Custom(behavior) = leaf(self.rules.parse(&chrd)) else {
+            return;
+        };
+        self.stack.clear();
+        self.pressed.push((behavior, keys)).ok();
+        self.behave(&chrd, |ctx| behavior.press(ctx));
+    }
+
+    // Runs a behavior hook, its reports go out like those of a chord
+    fn behave(&mut self, chord: &[Pressed], hook: impl FnOnce(&mut Context)) {
+        let mut keyboard: Vec<Keyb, REPORT> = Vec::new();
+        let mut ctx = Context::new(self.now, chord, self.held, &mut keyboard);
+        hook(&mut ctx);
+        self.overflow |= ctx.overflowed();
+        self.send(&mut keyboard, false);
+    }
+
+    // Released behaviors get ticks, a full list is flagged like any overflow
+    fn activate(&mut self, behavior: &'static dyn Behavior) {
+        if !self.active.contains(&behavior) && self.active.push(behavior).is_err() {
+            self.overflow = true;
+        }
+    }
+
+    fn alternate(&self) -> &'static [Keyb] {
+        let same = |a: &[Keyb]| {
+            a.len() == self.last.len()
+                && a.iter()
+                    .zip(&self.last)
+                    .all(|(a, b)| sideless(*a) == sideless(*b))
+        };
+        for (a, b) in self.alternates {
+            if same(a) {
+                return b;
+            }
+            if same(b) {
+                return a;
+            }
+        }
+        &[]
+    }
+
+    // Every report passes the report hooks and then the overrides, whatever
+    // made it, keyboard is left as sent. The last report is kept for
+    // Emit::Repeat unless the reports repeat
+    fn send(&mut self, keyboard: &mut Vec<Keyb, REPORT>, repeated: bool) {
+        if self.pipeline.report(keyboard).is_err() {
+            self.overflow = true;
+        }
+        if override_reports(self.rules.overrides, keyboard).is_err() {
+            self.overflow = true;
+        }
+        if keyboard.is_empty() {
+            return;
+        }
+        if !repeated {
+            let codes = keyboard.rsplit(|code| *code == Keyb::Out).next();
+            if let Some(last) = codes.and_then(|codes| Vec::from_slice(codes).ok()) {
+                if !last.is_empty() {
+                    self.last = last;
+                }
+            }
+        }
+        let appended = append(&mut self.reports, |reports| {
+            reports.extend_from_slice(keyboard).map_err(|_| Overflow)
+        });
+        if appended.is_err() {
+            self.overflow = true;
+        }
+    }
+}
+
+// How the stack is cut into chords, see Engine::completion
+enum Chords {
+    Completion(Completion),
+    Grouped(fn(Key) -> usize),
+    Stream(Stream),
+}
+
+// Passes emits on to the pipeline and collects the behaviors among them
+struct Dispatch<'p, P: Stage> {
+    pipeline: &'p mut P,
+    found: Vec<&'static dyn Behavior, 4>,
+    repeats: Vec<Emit<Keyb>, 4>,
+    // more behaviors or repeats than fit, see Engine::overflowed
+    dropped: bool,
+}
+
+impl<P: Stage> Stage for Dispatch<'_, P> {
+    fn emit(&mut self, chord: &[Pressed], emit: Emit<Keyb>) -> Emit<Keyb> {
+        let emit = self.pipeline.emit(chord, emit);
+        match leaf(emit) {
+            // modifiers around a behavior are dropped, it sends its own
+            Emit::Custom(behavior) => {
+                self.dropped |= self.found.push(behavior).is_err();
+                Emit::Custom(behavior)
+            }
+            Emit::Repeat | Emit::AltRepeat => {
+                self.dropped |= self.repeats.push(emit).is_err();
+                emit
+            }
+            _ => emit,
+        }
+    }
+
+    fn built<const R: usize>(
+        &mut self,
+        report: &mut Vec<Keyb, R>,
+        start: usize,
+    ) -> Result<(), Overflow> {
+        self.pipeline.built(report, start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lex::{qwerty::*, side},
+        parse::{
+            ChordEmit,
+            ChordEvent::{self, *},
+            Emit::*,
+        },
+    };
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use Event::*;
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const W_EVENTS: [ChordEvent; 1] = [On(W)];
+    const SHIFT_EVENTS: [ChordEvent; 2] = [On(D), Any];
+    const RULES: [ChordEmit<Keyb>; 3] = [
+        ChordEmit::new(&SHIFT_EVENTS, Shift(&Identity)),
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&W_EVENTS, Code(Keyb::W)),
+    ];
+
+    fn tap<const S: usize, const R: usize>(engine: &mut Engine<S, R>, key: Key) {
+        engine.feed(Down(key));
+        engine.feed(Up(key));
+    }
+
+    #[test]
+    fn reports_pile_up_until_drained() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        tap(&mut engine, Q.into());
+        tap(&mut engine, W.into());
+        assert_eq!(
+            &[Keyb::Q, Keyb::Out, Keyb::W],
+            engine.drain_reports().as_slice()
+        );
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn held_keys() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        engine.feed(Down(D.into()));
+        assert!(engine.is_held(D.into()));
+        assert_eq!(Some(D.into()), engine.layer());
+        tap(&mut engine, Q.into());
+        assert!(engine.drain_reports().is_empty());
+        engine.feed(Up(D.into()));
+        assert!(!engine.is_held(D.into()));
+        assert_eq!(None, engine.layer());
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn full_buffers() {
+        // reports past the buffer are dropped and flagged
+        let mut engine: Engine<16, 4> = Engine::new(Rules::ordered(&RULES));
+        for key in [Q, W, Q] {
+            tap(&mut engine, key.into());
+        }
+        assert!(engine.overflowed());
+        assert!(!engine.overflowed());
+        assert_eq!(
+            &[Keyb::Q, Keyb::Out, Keyb::W],
+            engine.drain_reports().as_slice()
+        );
+
+        // so are events past the stack, the keys held stay stuck until cleared
+        let mut engine: Engine<2, 8> = Engine::new(Rules::ordered(&RULES));
+        for event in [Down(Q.0), Down(W.0), Down(D.0), Up(Q.0)] {
+            engine.feed(event);
+        }
+        assert!(engine.overflowed());
+        assert!(engine.drain_reports().is_empty());
+        engine.clear();
+        tap(&mut engine, Q.into());
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+
+        // and the reports of a chord past its own buffer
+        let mut engine = Engine::<16, 16>::new(Rules::ordered(&RULES)).capacities::<16, 2>();
+        engine.feed(Down(D.into()));
+        for key in [Q, W] {
+            engine.feed(Down(key.into()));
+            engine.feed(Up(key.into()));
+        }
+        engine.feed(Up(D.into()));
+        assert!(engine.overflowed());
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn clock_releases_stuck_keys() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES)).watchdog(100);
+        engine.tick(10);
+        engine.feed(Down(Q.into()));
+        engine.tick(50);
+        assert!(engine.is_held(Q.into()));
+        engine.tick(200);
+        assert_eq!(200, engine.now());
+        assert!(!engine.is_held(Q.into()));
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn clear() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        engine.feed(Down(Q.into()));
+        engine.clear();
+        assert!(!engine.is_held(Q.into()));
+        tap(&mut engine, W.into());
+        assert_eq!(&[Keyb::W], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn chords() {
+        let shifted = [Down(D.0), Down(K.0), Up(K.0), Up(D.0)];
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&REPEAT_RULES));
+        for event in shifted {
+            engine.feed(event);
+        }
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::UpArrow],
+            engine.drain_reports().as_slice()
+        );
+
+        // one chord per hand, D alone emits nothing
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&REPEAT_RULES)).grouped(side);
+        for event in shifted {
+            engine.feed(event);
+        }
+        assert_eq!(&[Keyb::UpArrow], engine.drain_reports().as_slice());
+
+        // the held root goes with each inner key as it is released
+        let mut engine: Engine<16, 8> =
+            Engine::new(Rules::ordered(&REPEAT_RULES)).stream(Trigger::Release);
+        for event in &shifted[..3] {
+            engine.feed(*event);
+        }
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::UpArrow],
+            engine.drain_reports().as_slice()
+        );
+
+        // every key held when the first one goes up
+        let mut engine: Engine<16, 8> =
+            Engine::new(Rules::ordered(&REPEAT_RULES)).completion(Completion::FirstRelease);
+        for event in [Down(D.0), Down(K.0), Up(D.0), Up(K.0)] {
+            engine.feed(event);
+        }
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::UpArrow],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn fallback_and_early() {
+        // the rule for Q leaves W unused
+        let overlap = [Down(Q.0), Down(W.0), Up(W.0), Up(Q.0)];
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES));
+        for event in overlap {
+            engine.feed(event);
+        }
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+        let mut engine: Engine<16, 8> =
+            Engine::new(Rules::ordered(&RULES)).fallback(Fallback::LastOnly);
+        for event in overlap {
+            engine.feed(event);
+        }
+        assert_eq!(&[Keyb::W], engine.drain_reports().as_slice());
+
+        // Q goes out on press, once
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES)).early();
+        engine.feed(Down(Q.0));
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+        for event in &overlap[1..] {
+            engine.feed(*event);
+        }
+        assert!(engine.drain_reports().is_empty());
+        // not when further keys may change what it emits
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES))
+            .fallback(Fallback::LastOnly)
+            .early();
+        engine.feed(Down(Q.0));
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    static STRAYS: AtomicU32 = AtomicU32::new(0);
+
+    #[test]
+    fn on_recovery() {
+        let mut engine: Engine<16, 8> = Engine::new(Rules::ordered(&RULES))
+            .watchdog(100)
+            .on_recovery(|recovery| {
+                if let Recovery::Stray(_) = recovery {
+                    STRAYS.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        engine.feed(Up(Q.0));
+        assert_eq!(1, STRAYS.load(Ordering::Relaxed));
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    const K_EVENTS: [ChordEvent; 1] = [On(K)];
+    const J_EVENTS: [ChordEvent; 1] = [On(J)];
+    const REPEAT_EVENTS: [ChordEvent; 1] = [On(SEMICOLON)];
+    const ALT_EVENTS: [ChordEvent; 1] = [On(L)];
+    const REPEAT_RULES: [ChordEmit<Keyb>; 6] = [
+        ChordEmit::new(&SHIFT_EVENTS, Shift(&Identity)),
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&K_EVENTS, Code(Keyb::UpArrow)),
+        ChordEmit::new(&J_EVENTS, Code(Keyb::DownArrow)),
+        ChordEmit::new(&REPEAT_EVENTS, Repeat),
+        ChordEmit::new(&ALT_EVENTS, AltRepeat),
+    ];
+    const ALTERNATES: [(&[Keyb], &[Keyb]); 1] = [(&[Keyb::UpArrow], &[Keyb::DownArrow])];
+
+    #[test]
+    fn repeat() {
+        let mut engine: Engine<16, 16> =
+            Engine::new(Rules::ordered(&REPEAT_RULES)).alternates(&ALTERNATES);
+        // nothing to repeat yet
+        tap(&mut engine, SEMICOLON.into());
+        assert!(engine.drain_reports().is_empty());
+        for event in [Down(D.into()), Down(Q.into()), Up(Q.into()), Up(D.into())] {
+            engine.feed(event);
+        }
+        tap(&mut engine, SEMICOLON.into());
+        assert_eq!(
+            &[
+                Keyb::LeftShift,
+                Keyb::Q,
+                Keyb::Out,
+                Keyb::LeftShift,
+                Keyb::Q
+            ],
+            engine.drain_reports().as_slice()
+        );
+        // no counterpart
+        tap(&mut engine, L.into());
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn alt_repeat() {
+        let mut engine: Engine<16, 16> =
+            Engine::new(Rules::ordered(&REPEAT_RULES)).alternates(&ALTERNATES);
+        tap(&mut engine, K.into());
+        tap(&mut engine, L.into());
+        // repeats remember what came before them
+        tap(&mut engine, L.into());
+        tap(&mut engine, SEMICOLON.into());
+        tap(&mut engine, J.into());
+        tap(&mut engine, L.into());
+        assert_eq!(
+            &[
+                Keyb::UpArrow,
+                Keyb::Out,
+                Keyb::DownArrow,
+                Keyb::Out,
+                Keyb::DownArrow,
+                Keyb::Out,
+                Keyb::UpArrow,
+                Keyb::Out,
+                Keyb::DownArrow,
+                Keyb::Out,
+                Keyb::UpArrow
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+}
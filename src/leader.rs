@@ -0,0 +1,292 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    lex::{Event, Millis, Pressed},
+    parse::{Emit, Rules},
+    pipeline::Stage,
+    report::{append, build_keyboard_report, Overflow},
+};
+
+// One chord of a leader sequence, a single key or several, with the emit of
+// the sequence ending here and the chords that may follow. A node with both is
+// a prefix conflict, it waits for the timeout or a chord that does not follow
+#[derive(Debug)]
+pub struct Node(
+    pub &'static [Pressed],
+    pub Option<Emit<Keyb>>,
+    pub &'static [Node],
+);
+
+// After the leader chord, chords go to the sequence table instead of the
+// rules until a sequence ends, fails or times out
+pub struct Leader {
+    key: &'static [Pressed],
+    table: &'static [Node],
+    timeout: Millis,
+    // sent when a sequence is not in the table
+    unknown: Option<Emit<Keyb>>,
+    // the keys that may follow, None while not leading
+    level: Option<&'static [Node]>,
+    matched: Option<Emit<Keyb>>,
+    pending: Option<Emit<Keyb>>,
+    typed: bool,
+    now: Millis,
+    last: Millis,
+}
+
+impl Leader {
+    pub const fn new(key: &'static [Pressed], table: &'static [Node], timeout: Millis) -> Self {
+        Leader {
+            key,
+            table,
+            timeout,
+            unknown: None,
+            level: None,
+            matched: None,
+            pending: None,
+            typed: false,
+            now: 0,
+            last: 0,
+        }
+    }
+
+    pub const fn unknown(self, emit: Emit<Keyb>) -> Self {
+        Leader {
+            unknown: Some(emit),
+            ..self
+        }
+    }
+
+    pub fn is_leading(&self) -> bool {
+        self.level.is_some()
+    }
+
+    fn finish(&mut self, emit: Option<Emit<Keyb>>) {
+        self.pending = emit;
+        self.level = None;
+        self.matched = None;
+    }
+
+    // The sequence goes out before the report, it is dropped if both do not fit
+    fn send<const R: usize>(&mut self, report: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+        let Some(emit) = self.pending.take() else {
+            return Ok(());
+        };
+        let mut keyboard: Vec<Keyb, R> = Vec::new();
+        let Pressed(first) = self.key[0];
+        let sent = build_keyboard_report(emit, emit, &first, &mut keyboard).and_then(|()| {
+            if report.is_empty() {
+                return Ok(());
+            }
+            append(&mut keyboard, |keyboard| {
+                keyboard.extend_from_slice(report).map_err(|_| Overflow)
+            })
+        });
+        if sent.is_ok() {
+            *report = keyboard;
+        }
+        sent
+    }
+}
+
+impl Stage for Leader {
+    fn events<const N: usize>(&mut self, _events: &mut Vec<Event, N>, now: Millis) {
+        self.now = now;
+    }
+
+    fn chord<const N: usize>(&mut self, chord: &mut Vec<Pressed, N>) {
+        let Some(level) = self.level else {
+            if chord.as_slice() == self.key {
+                self.level = Some(self.table);
+                self.typed = false;
+                self.last = self.now;
+                chord.clear();
+            }
+            return;
+        };
+        if chord.is_empty() {
+            return;
+        }
+        self.typed = true;
+        self.last = self.now;
+        match level.iter().find(|node| node.0 == chord.as_slice()) {
+            Some(Node(_, emit, [])) => self.finish(emit.or(self.unknown)),
+            Some(Node(_, emit, next)) => {
+                self.level = Some(next);
+                self.matched = *emit;
+            }
+            // the sequence so far stands, the key is typed as usual
+            None if self.matched.is_some() => {
+                self.finish(self.matched);
+                return;
+            }
+            None => self.finish(self.unknown),
+        }
+        chord.clear();
+    }
+
+    fn report<const R: usize>(&mut self, report: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+        self.send(report)
+    }
+
+    fn tick<const R: usize>(
+        &mut self,
+        now: Millis,
+        _rules: &Rules<Keyb>,
+        report: &mut Vec<Keyb, R>,
+    ) -> Result<(), Overflow> {
+        self.now = now;
+        if self.level.is_some() && now.wrapping_sub(self.last) >= self.timeout {
+            match self.matched {
+                Some(emit) => self.finish(Some(emit)),
+                None if self.typed => self.finish(self.unknown),
+                None => self.finish(None),
+            }
+        }
+        self.send(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alias,
+        engine::Engine,
+        lex::{qwerty::*, Event::*},
+        parse::{ChordEmit, ChordEvent, ChordEvent::On, Emit::*},
+    };
+
+    alias!(LEAD, Left, K17);
+
+    // g alone and g s are both sequences, q only leads on to w, the chord
+    // q+w is a sequence of its own
+    const TABLE: [Node; 3] = [
+        Node(
+            &[G],
+            Some(Code(Keyb::F1)),
+            &[Node(&[S], Some(Ctrl(&Code(Keyb::S))), &[])],
+        ),
+        Node(&[Q], None, &[Node(&[W], Some(Code(Keyb::F2)), &[])]),
+        Node(&[Q, W], Some(Code(Keyb::F3)), &[]),
+    ];
+
+    const X_EVENTS: [ChordEvent; 1] = [On(X)];
+    const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&X_EVENTS, Code(Keyb::X))];
+
+    fn engine() -> Engine<'static, 16, 16, Leader> {
+        Engine::new(Rules::ordered(&RULES))
+            .pipeline(Leader::new(&[LEAD], &TABLE, 1000).unknown(Code(Keyb::Escape)))
+    }
+
+    fn tap<const N: usize, const R: usize>(
+        engine: &mut Engine<'static, 16, 16, Leader, N, R>,
+        key: Pressed,
+        now: Millis,
+    ) {
+        engine.tick(now);
+        engine.feed(Down(key.into()));
+        engine.feed(Up(key.into()));
+    }
+
+    #[test]
+    fn sequence() {
+        let mut engine = engine();
+        tap(&mut engine, LEAD, 0);
+        assert!(engine.stages().is_leading());
+        tap(&mut engine, G, 100);
+        tap(&mut engine, S, 200);
+        assert!(!engine.stages().is_leading());
+        assert_eq!(
+            &[Keyb::LeftControl, Keyb::S],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn prefix_conflict_times_out() {
+        let mut engine = engine();
+        tap(&mut engine, LEAD, 0);
+        tap(&mut engine, G, 100);
+        engine.tick(1000);
+        assert!(engine.drain_reports().is_empty());
+        engine.tick(1100);
+        assert_eq!(&[Keyb::F1], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn prefix_conflict_other_key() {
+        let mut engine = engine();
+        tap(&mut engine, LEAD, 0);
+        tap(&mut engine, G, 100);
+        tap(&mut engine, X, 200);
+        assert_eq!(
+            &[Keyb::F1, Keyb::Out, Keyb::X],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn unknown_sequence() {
+        let mut engine = engine();
+        tap(&mut engine, LEAD, 0);
+        tap(&mut engine, Q, 100);
+        tap(&mut engine, E, 200);
+        assert_eq!(&[Keyb::Escape], engine.drain_reports().as_slice());
+
+        // stopping half way is unknown too
+        tap(&mut engine, LEAD, 300);
+        tap(&mut engine, Q, 400);
+        engine.tick(1400);
+        assert_eq!(&[Keyb::Escape], engine.drain_reports().as_slice());
+
+        // leading alone just ends
+        tap(&mut engine, LEAD, 2000);
+        engine.tick(3000);
+        assert!(engine.drain_reports().is_empty());
+        assert!(!engine.stages().is_leading());
+    }
+
+    #[test]
+    fn rules_apply_outside() {
+        let mut engine = engine();
+        tap(&mut engine, X, 0);
+        assert_eq!(&[Keyb::X], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn chord_in_sequence() {
+        let mut engine = engine();
+        tap(&mut engine, LEAD, 0);
+        engine.tick(100);
+        for event in [Down(Q.0), Down(W.0), Up(W.0), Up(Q.0)] {
+            engine.feed(event);
+        }
+        assert!(!engine.stages().is_leading());
+        assert_eq!(&[Keyb::F3], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn sequence_past_report() {
+        let mut engine: Engine<'static, 16, 16, Leader, 8, 2> = engine().capacities();
+        tap(&mut engine, LEAD, 0);
+        tap(&mut engine, G, 100);
+        // F1 and X do not fit one chord report together, X stays
+        tap(&mut engine, X, 200);
+        assert!(engine.overflowed());
+        assert_eq!(&[Keyb::X], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn sequence_past_empty_report() {
+        // Ctrl+S does not fit on its own
+        let mut engine: Engine<'static, 16, 16, Leader, 8, 1> = engine().capacities();
+        tap(&mut engine, LEAD, 300);
+        tap(&mut engine, G, 400);
+        tap(&mut engine, S, 500);
+        assert!(!engine.stages().is_leading());
+        assert!(engine.overflowed());
+        assert!(engine.drain_reports().is_empty());
+    }
+}
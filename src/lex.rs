@@ -133,69 +133,348 @@ pub mod dvorak {
 
 use heapless::Vec;
 
+use crate::report::Overflow;
+
 pub const STACK_SIZE: usize = 128;
 pub const PRESS_SIZE: usize = 64;
 pub const REPORT_SIZE: usize = 32; // TODO: figure out how to handle Emit::String
 
-pub fn chord(stack: &mut Vec<Event, STACK_SIZE>) -> Vec<Pressed, PRESS_SIZE> {
-    let mut pressed: Vec<Pressed, PRESS_SIZE> = Vec::new();
-    if !stack.is_empty() {
-        rec_chord(stack, &mut pressed);
-        let Event::Down(root) = stack[0] else {
-            // Stack can never start with an Event::Up
-            stack.clear(); // Something _very_ bad has happened
-            pressed.clear();
-            return pressed;
-        };
-        if pressed.is_empty() {
-            return pressed;
-        }
-        let Pressed(first) = pressed[0];
-        if root != first {
-            pressed.clear();
-            return pressed;
+pub type Millis = u32;
+
+// When a chord is complete and leaves the stack
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Completion {
+    // The first key pressed is released, keys released after it are not part of the chord
+    #[default]
+    Root,
+    // Any key is released, every key held at that moment is part of the chord
+    FirstRelease,
+    // Every key is released again, as in steno
+    AllReleased,
+}
+
+pub fn chord<const S: usize>(stack: &mut Vec<Event, S>) -> Vec<Pressed, PRESS_SIZE> {
+    chord_with(stack, Completion::default())
+}
+
+// Like chord, for a chord of at most N keys, the presses past N are dropped
+pub fn chord_sized<const S: usize, const N: usize>(stack: &mut Vec<Event, S>) -> Vec<Pressed, N> {
+    let mut pressed = Vec::new();
+    chord_into(stack, Completion::default(), &mut pressed).ok();
+    pressed
+}
+
+pub fn chord_with<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    completion: Completion,
+) -> Vec<Pressed, PRESS_SIZE> {
+    let mut pressed = Vec::new();
+    chord_into(stack, completion, &mut pressed).ok();
+    pressed
+}
+
+// Like chord_with, the chord goes to pressed. Presses past its capacity are
+// dropped, the chord leaves the stack all the same
+pub fn chord_into<const S: usize, const N: usize>(
+    stack: &mut Vec<Event, S>,
+    completion: Completion,
+    pressed: &mut Vec<Pressed, N>,
+) -> Result<(), Overflow> {
+    match completion {
+        Completion::Root => root_chord(stack, pressed),
+        Completion::FirstRelease => {
+            drop_released(stack);
+            match stack.iter().position(|e| matches!(e, Event::Up(_))) {
+                Some(up) => drain_chord(stack, up, pressed),
+                None => Ok(()),
+            }
         }
-        for press in &pressed {
-            let Pressed(press_key) = press;
-            // remove events from stack used by presses
-            stack.retain(|e| match e {
-                Event::Down(event_key) => *press_key != *event_key,
-                Event::Up(event_key) => *press_key != *event_key,
-            });
+        Completion::AllReleased => {
+            // a stray Up releases nothing, the chord waits for every Down
+            drop_released(stack);
+            let mut held: u128 = 0;
+            for (ix, event) in stack.iter().enumerate() {
+                match event {
+                    Event::Down(key) => held |= bit(*key),
+                    Event::Up(key) => held &= !bit(*key),
+                }
+                if held == 0 {
+                    return drain_chord(stack, ix, pressed);
+                }
+            }
+            Ok(())
         }
     }
+}
+
+// Keys in different groups never share a chord, each group has a root of its
+// own, e.g. one group per hand, see side
+pub fn chord_grouped<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    group: impl Fn(Key) -> usize,
+) -> Vec<Pressed, PRESS_SIZE> {
+    let mut pressed = Vec::new();
+    chord_grouped_into(stack, group, &mut pressed).ok();
     pressed
 }
 
-fn rec_chord(stack: &[Event], pressed: &mut Vec<Pressed, PRESS_SIZE>) {
-    assert!(!stack.is_empty(), "Stack cannot be empty in rec_chord");
-    let root_key = if !pressed.is_empty() {
-        Some(pressed[0])
-    } else {
-        None
+// Like chord_grouped, see chord_into
+pub fn chord_grouped_into<const S: usize, const N: usize>(
+    stack: &mut Vec<Event, S>,
+    group: impl Fn(Key) -> usize,
+    pressed: &mut Vec<Pressed, N>,
+) -> Result<(), Overflow> {
+    drop_released(stack);
+    let mut first: Option<(usize, Vec<Pressed, N>, Result<(), Overflow>)> = None;
+    let mut seen: Vec<usize, PRESS_SIZE> = Vec::new();
+    for event in stack.iter() {
+        let Event::Down(root) = event else {
+            continue;
+        };
+        let id = group(*root);
+        if seen.contains(&id) {
+            continue;
+        }
+        // past PRESS_SIZE groups one may be looked at twice, to the same end
+        seen.push(id).ok();
+        let mut sub: Vec<Event, S> = stack
+            .iter()
+            .filter(|e| group(Key::from(**e)) == id)
+            .copied()
+            .collect();
+        let mut chord = Vec::new();
+        let fits = root_chord(&mut sub, &mut chord);
+        if chord.is_empty() {
+            continue;
+        }
+        // in time order, the chord whose root went up first
+        let down = stack.iter().position(|e| *e == Event::Down(*root)).unwrap();
+        let up = down
+            + stack[down..]
+                .iter()
+                .position(|e| *e == Event::Up(*root))
+                .unwrap();
+        if first.as_ref().is_none_or(|(done, _, _)| up < *done) {
+            first = Some((up, chord, fits));
+        }
+    }
+    let Some((_, chord, fits)) = first else {
+        return Ok(());
     };
-    if let Some(Pressed(root_key)) = root_key {
-        if let Event::Up(key) = &stack[0] {
-            if root_key == *key {
-                return;
+    for Pressed(key) in &chord {
+        remove_press(stack, *key);
+    }
+    *pressed = chord;
+    fits
+}
+
+pub fn side(key: Key) -> usize {
+    matches!(key, Key::Left(_)) as usize
+}
+
+// keys pressed up to end make the chord, in press order
+fn drain_chord<const S: usize, const N: usize>(
+    stack: &mut Vec<Event, S>,
+    end: usize,
+    pressed: &mut Vec<Pressed, N>,
+) -> Result<(), Overflow> {
+    let mut fits = Ok(());
+    for event in &stack[..=end] {
+        if let Event::Down(key) = event {
+            if !pressed.contains(&Pressed(*key)) && pressed.push(Pressed(*key)).is_err() {
+                fits = Err(Overflow);
             }
         }
     }
-    if let Event::Down(start_key) = &stack[0] {
-        for entry in stack {
-            if let Event::Up(key) = entry {
-                if key == start_key {
-                    if pressed.push(Pressed(*start_key)).is_err() {
-                        panic!("Should have enough capacity to push pressed");
-                    }
-                    break;
+    let mut ix = 0;
+    stack.retain(|_| {
+        ix += 1;
+        ix > end + 1
+    });
+    fits
+}
+
+// drop the Up of keys that went out with a chord while still held
+fn drop_released<const S: usize>(stack: &mut Vec<Event, S>) {
+    let mut ix = 0;
+    while ix < stack.len() {
+        match stack[ix] {
+            Event::Up(key) if !stack[..ix].contains(&Event::Down(key)) => {
+                stack.remove(ix);
+            }
+            _ => ix += 1,
+        }
+    }
+}
+
+// Both sides fit in one u128 mask
+pub(crate) fn index(key: Key) -> usize {
+    u8::from(Event::Up(key)) as usize
+}
+
+pub(crate) fn bit(key: Key) -> u128 {
+    1 << index(key)
+}
+
+fn root_chord<const S: usize, const N: usize>(
+    stack: &mut Vec<Event, S>,
+    pressed: &mut Vec<Pressed, N>,
+) -> Result<(), Overflow> {
+    const { assert!(S <= 128, "stack positions must fit a u128 mask") };
+    let Some(first) = stack.first() else {
+        return Ok(());
+    };
+    let Event::Down(root) = *first else {
+        // Stack can never start with an Event::Up
+        stack.clear(); // Something _very_ bad has happened
+        return Ok(());
+    };
+    // the chord ends with the release of the root
+    let Some(end) = stack.iter().skip(1).position(|e| *e == Event::Up(root)) else {
+        return Ok(());
+    };
+    let end = end + 1;
+
+    // backwards: keys released at or after each position, presses that have one
+    let mut released: u128 = 0;
+    let mut chosen: u128 = 0;
+    for (ix, event) in stack.iter().enumerate().rev() {
+        match event {
+            Event::Up(key) => released |= bit(*key),
+            Event::Down(key) if ix < end && released & bit(*key) != 0 => chosen |= 1 << ix,
+            Event::Down(_) => {}
+        }
+    }
+
+    // forwards: presses in order, each with the first unused release after it,
+    // a key pressed twice without a release in between waits for two
+    let mut waiting = [0u8; 128];
+    let mut used: u128 = 0;
+    let mut fits = Ok(());
+    for (ix, event) in stack.iter().enumerate() {
+        match event {
+            Event::Down(key) if chosen & 1 << ix != 0 => {
+                if pressed.push(Pressed(*key)).is_err() {
+                    fits = Err(Overflow);
                 }
+                waiting[index(*key)] += 1;
+                used |= 1 << ix;
+            }
+            Event::Up(key) if waiting[index(*key)] > 0 => {
+                waiting[index(*key)] -= 1;
+                used |= 1 << ix;
+            }
+            _ => {}
+        }
+    }
+    let mut ix = 0;
+    stack.retain(|_| {
+        ix += 1;
+        used & 1 << (ix - 1) == 0
+    });
+    fits
+}
+
+// remove events from stack used by a press, later presses of the same key stay
+fn remove_press<const S: usize>(stack: &mut Vec<Event, S>, key: Key) {
+    if let Some(down) = stack.iter().position(|e| *e == Event::Down(key)) {
+        stack.remove(down);
+        if let Some(up) = stack[down..].iter().position(|e| *e == Event::Up(key)) {
+            stack.remove(down + up);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Trigger {
+    #[default]
+    Release,
+    // Only sensible when every root is a layer or modifier key, rolls turn into chords
+    Press,
+}
+
+// While the root is held it acts as a live context, every inner key makes a
+// chord [root, inner] of its own as soon as it triggers
+#[derive(Debug, Default)]
+pub struct Stream {
+    trigger: Trigger,
+    // root that already made a chord with an inner key, its own tap is swallowed
+    streamed: Option<Key>,
+    // inner keys emitted on press, their Up is dropped when it arrives
+    swallow: u128,
+}
+
+impl Stream {
+    pub fn new(trigger: Trigger) -> Self {
+        Stream {
+            trigger,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.streamed = None;
+        self.swallow = 0;
+    }
+}
+
+pub fn stream<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    state: &mut Stream,
+) -> Vec<Pressed, PRESS_SIZE> {
+    let mut pressed = Vec::new();
+    stream_into(stack, state, &mut pressed).ok();
+    pressed
+}
+
+// Like stream, see chord_into
+pub fn stream_into<const S: usize, const N: usize>(
+    stack: &mut Vec<Event, S>,
+    state: &mut Stream,
+    pressed: &mut Vec<Pressed, N>,
+) -> Result<(), Overflow> {
+    let mut ix = 0;
+    while ix < stack.len() {
+        match stack[ix] {
+            Event::Up(key) if state.swallow & bit(key) != 0 => {
+                state.swallow &= !bit(key);
+                stack.remove(ix);
             }
+            _ => ix += 1,
+        }
+    }
+    let Some(Event::Down(root)) = stack.first().copied() else {
+        stack.clear();
+        return Ok(());
+    };
+    if stack.contains(&Event::Up(root)) {
+        if state.streamed.take() == Some(root) {
+            remove_press(stack, root);
+            return Ok(());
         }
+        return root_chord(stack, pressed);
     }
-    if stack.len() >= 2 {
-        rec_chord(&stack[1..], pressed);
+    let ready = stack
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(ix, e)| match e {
+            Event::Down(key) if state.trigger == Trigger::Press => Some(*key),
+            Event::Down(key) if stack[ix..].contains(&Event::Up(*key)) => Some(*key),
+            _ => None,
+        });
+    let Some(key) = ready else {
+        return Ok(());
+    };
+    state.streamed = Some(root);
+    if !stack.contains(&Event::Up(key)) {
+        state.swallow |= bit(key);
     }
+    remove_press(stack, key);
+    pressed
+        .push(Pressed(root))
+        .and_then(|()| pressed.push(Pressed(key)))
+        .map_err(|_| Overflow)
 }
 
 #[cfg(test)]
@@ -204,6 +483,112 @@ mod tests {
     use super::Key::*;
     use super::*;
 
+    // The former recursive chord recognition, kept to compare against
+    fn reference_chord(stack: &mut Vec<Event, STACK_SIZE>) -> Vec<Pressed, PRESS_SIZE> {
+        let mut pressed: Vec<Pressed, PRESS_SIZE> = Vec::new();
+        if !stack.is_empty() {
+            rec_chord(stack, &mut pressed);
+            let Event::Down(root) = stack[0] else {
+                // Stack can never start with an Event::Up
+                stack.clear(); // Something _very_ bad has happened
+                pressed.clear();
+                return pressed;
+            };
+            if pressed.is_empty() {
+                return pressed;
+            }
+            let Pressed(first) = pressed[0];
+            if root != first {
+                pressed.clear();
+                return pressed;
+            }
+            for press in &pressed {
+                let Pressed(press_key) = press;
+                remove_press(stack, *press_key);
+            }
+        }
+        pressed
+    }
+
+    fn rec_chord(stack: &[Event], pressed: &mut Vec<Pressed, PRESS_SIZE>) {
+        assert!(!stack.is_empty(), "Stack cannot be empty in rec_chord");
+        let root_key = if !pressed.is_empty() {
+            Some(pressed[0])
+        } else {
+            None
+        };
+        if let Some(Pressed(root_key)) = root_key {
+            if let Event::Up(key) = &stack[0] {
+                if root_key == *key {
+                    return;
+                }
+            }
+        }
+        if let Event::Down(start_key) = &stack[0] {
+            for entry in stack {
+                if let Event::Up(key) = entry {
+                    if key == start_key {
+                        if pressed.push(Pressed(*start_key)).is_err() {
+                            panic!("Should have enough capacity to push pressed");
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        if stack.len() >= 2 {
+            rec_chord(&stack[1..], pressed);
+        }
+    }
+
+    #[test]
+    fn same_as_reference() {
+        let keys = [
+            Left(KeyId::K1),
+            Left(KeyId::K2),
+            Left(KeyId::K40),
+            Right(KeyId::K1),
+            Right(KeyId::K40),
+        ];
+        let mut seed: u32 = 0x9e37_79b9;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as usize % n
+        };
+        for round in 0..20_000 {
+            let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+            let mut held: std::vec::Vec<Key> = std::vec::Vec::new();
+            for _ in 0..random(24) {
+                let key = keys[random(keys.len())];
+                // every other round also has double presses and stray releases
+                let event = if round % 2 == 1 && random(4) == 0 {
+                    if random(2) == 0 {
+                        Down(key)
+                    } else {
+                        Up(key)
+                    }
+                } else if let Some(ix) = held.iter().position(|k| *k == key) {
+                    held.remove(ix);
+                    Up(key)
+                } else {
+                    held.push(key);
+                    Down(key)
+                };
+                stack.push(event).unwrap();
+                let mut expected = stack.clone();
+                let before = stack.clone();
+                loop {
+                    let presses = chord(&mut stack);
+                    assert_eq!(reference_chord(&mut expected), presses, "{:?}", stack);
+                    assert_eq!(expected, stack, "{:?}", before);
+                    if presses.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn single_key() {
         let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
@@ -295,6 +680,24 @@ mod tests {
         assert_eq!(Pressed(Left(KeyId::K2)), presses[1]);
     }
 
+    #[test]
+    fn later_press_of_chord_key_stays() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(Left(KeyId::K1))).unwrap();
+        stack.push(Down(Left(KeyId::K2))).unwrap();
+        stack.push(Up(Left(KeyId::K2))).unwrap();
+        // pressed again, still held when the chord completes
+        stack.push(Down(Left(KeyId::K2))).unwrap();
+        stack.push(Up(Left(KeyId::K1))).unwrap();
+
+        let presses = chord(&mut stack);
+        assert_eq!(
+            &[Pressed(Left(KeyId::K1)), Pressed(Left(KeyId::K2))],
+            presses.as_slice()
+        );
+        assert_eq!(&[Down(Left(KeyId::K2))], stack.as_slice());
+    }
+
     #[test]
     fn two_key_chord_in_eval() {
         let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
@@ -337,6 +740,345 @@ mod tests {
         assert_eq!(Pressed(Left(KeyId::K3)), presses[0]);
     }
 
+    // Every chord the events make, checking the stack after each event
+    fn completed(
+        completion: Completion,
+        events: &[Event],
+    ) -> std::vec::Vec<std::vec::Vec<Pressed>> {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut chords = std::vec::Vec::new();
+        for event in events {
+            stack.push(*event).unwrap();
+            loop {
+                let presses = chord_with(&mut stack, completion);
+                if presses.is_empty() {
+                    break;
+                }
+                chords.push(presses.to_vec());
+            }
+        }
+        assert!(stack.is_empty());
+        chords
+    }
+
+    #[test]
+    fn completion_policies() {
+        let (k1, k2, k3) = (Left(KeyId::K1), Left(KeyId::K2), Left(KeyId::K3));
+        let (p1, p2, p3) = (Pressed(k1), Pressed(k2), Pressed(k3));
+        let single_key = [Down(k1), Up(k1)];
+        let two_single_key_strokes = [Down(k1), Up(k1), Down(k2), Up(k2)];
+        let two_key_chord = [Down(k1), Down(k2), Up(k2), Up(k1)];
+        let surplus_then_single = [Down(k1), Down(k2), Up(k2), Up(k1), Down(k3), Up(k3)];
+        for completion in [
+            Completion::Root,
+            Completion::FirstRelease,
+            Completion::AllReleased,
+        ] {
+            assert_eq!(vec![vec![p1]], completed(completion, &single_key));
+            assert_eq!(
+                vec![vec![p1], vec![p2]],
+                completed(completion, &two_single_key_strokes)
+            );
+            assert_eq!(vec![vec![p1, p2]], completed(completion, &two_key_chord));
+            assert_eq!(
+                vec![vec![p1, p2], vec![p3]],
+                completed(completion, &surplus_then_single)
+            );
+        }
+
+        let two_single_weird_timing = [Down(k1), Down(k2), Up(k1), Up(k2)];
+        assert_eq!(
+            vec![vec![p1], vec![p2]],
+            completed(Completion::Root, &two_single_weird_timing)
+        );
+        assert_eq!(
+            vec![vec![p1, p2]],
+            completed(Completion::FirstRelease, &two_single_weird_timing)
+        );
+        assert_eq!(
+            vec![vec![p1, p2]],
+            completed(Completion::AllReleased, &two_single_weird_timing)
+        );
+
+        // a steno stroke, keys overlap without ever all being held together
+        let stroke = [Down(k1), Down(k2), Up(k1), Down(k3), Up(k2), Up(k3)];
+        assert_eq!(
+            vec![vec![p1], vec![p2], vec![p3]],
+            completed(Completion::Root, &stroke)
+        );
+        assert_eq!(
+            vec![vec![p1, p2], vec![p3]],
+            completed(Completion::FirstRelease, &stroke)
+        );
+        assert_eq!(
+            vec![vec![p1, p2, p3]],
+            completed(Completion::AllReleased, &stroke)
+        );
+    }
+
+    #[test]
+    fn all_released_after_stray_up() {
+        let k1 = Left(KeyId::K1);
+        let k2 = Left(KeyId::K2);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        // the Up of k2 belongs to an earlier chord
+        for event in [Up(k2), Down(k1), Up(k1)] {
+            stack.push(event).unwrap();
+        }
+        assert_eq!(
+            &[Pressed(k1)],
+            chord_with(&mut stack, Completion::AllReleased).as_slice()
+        );
+        assert!(stack.is_empty());
+
+        // k2 is still held, an Up of k1 alone ends nothing
+        for event in [Down(k1), Down(k2), Up(k1), Up(k1)] {
+            stack.push(event).unwrap();
+        }
+        assert!(chord_with(&mut stack, Completion::AllReleased).is_empty());
+        stack.push(Up(k2)).unwrap();
+        assert_eq!(
+            &[Pressed(k1), Pressed(k2)],
+            chord_with(&mut stack, Completion::AllReleased).as_slice()
+        );
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn first_release_ends_early() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(Left(KeyId::K1))).unwrap();
+        stack.push(Down(Left(KeyId::K2))).unwrap();
+        stack.push(Up(Left(KeyId::K2))).unwrap();
+
+        let presses = chord_with(&mut stack, Completion::FirstRelease);
+        assert_eq!(2, presses.len());
+        assert!(stack.is_empty());
+
+        // the late Up of the root is dropped
+        stack.push(Up(Left(KeyId::K1))).unwrap();
+        assert!(chord_with(&mut stack, Completion::FirstRelease).is_empty());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn every_key_at_once() {
+        // more keys than PRESS_SIZE, the chord keeps the first that fit
+        let all = || {
+            (0..40)
+                .chain(0b0100_0000..0b0100_0000 + 40)
+                .map(Event::from)
+        };
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        for up in all() {
+            stack.push(Event::Down(up.into())).unwrap();
+        }
+        stack.push(all().next().unwrap()).unwrap();
+        let mut rest = stack.clone();
+        let presses = chord_with(&mut stack, Completion::FirstRelease);
+        assert_eq!(PRESS_SIZE, presses.len());
+        assert!(stack.is_empty());
+
+        let mut presses: Vec<Pressed, 80> = Vec::new();
+        assert_eq!(
+            Ok(()),
+            chord_into(&mut rest, Completion::FirstRelease, &mut presses)
+        );
+        assert_eq!(80, presses.len());
+    }
+
+    // Like completed, for chords that are grouped
+    fn grouped(
+        group: impl Fn(Key) -> usize + Copy,
+        events: &[Event],
+    ) -> std::vec::Vec<std::vec::Vec<Pressed>> {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut chords = std::vec::Vec::new();
+        for event in events {
+            stack.push(*event).unwrap();
+            loop {
+                let presses = chord_grouped(&mut stack, group);
+                if presses.is_empty() {
+                    break;
+                }
+                chords.push(presses.to_vec());
+            }
+        }
+        assert!(stack.is_empty());
+        chords
+    }
+
+    #[test]
+    fn chords_per_side() {
+        let (q, w) = (Left(KeyId::K1), Left(KeyId::K2));
+        let (j, k) = (Right(KeyId::K9), Right(KeyId::K8));
+        // the right hand types while the left holds a chord
+        let events = [
+            Down(q),
+            Down(w),
+            Down(j),
+            Up(j),
+            Down(k),
+            Up(k),
+            Up(w),
+            Up(q),
+        ];
+        assert_eq!(
+            vec![
+                vec![Pressed(j)],
+                vec![Pressed(k)],
+                vec![Pressed(q), Pressed(w)]
+            ],
+            grouped(side, &events)
+        );
+        // one group is the old single chord
+        assert_eq!(
+            vec![vec![Pressed(q), Pressed(w), Pressed(j), Pressed(k)]],
+            grouped(|_| 0, &events)
+        );
+
+        // two chords overlap, the one whose root goes up first comes first
+        let events = [
+            Down(j),
+            Down(q),
+            Down(w),
+            Down(k),
+            Up(w),
+            Up(k),
+            Up(j),
+            Up(q),
+        ];
+        assert_eq!(
+            vec![vec![Pressed(j), Pressed(k)], vec![Pressed(q), Pressed(w)]],
+            grouped(side, &events)
+        );
+    }
+
+    #[test]
+    fn chords_per_declared_group() {
+        let (q, w) = (Left(KeyId::K1), Left(KeyId::K2));
+        let thumb = Left(KeyId::K16);
+        let thumbs = |key: Key| (KeyId::from(key) == KeyId::K16) as usize;
+        let events = [Down(q), Down(thumb), Down(w), Up(w), Up(q), Up(thumb)];
+        assert_eq!(
+            vec![vec![Pressed(q), Pressed(w)], vec![Pressed(thumb)]],
+            grouped(thumbs, &events)
+        );
+    }
+
+    #[test]
+    fn grouped_stray_up_and_many_groups() {
+        let (q, w) = (Left(KeyId::K1), Left(KeyId::K2));
+        // the Up of a key that went out with an earlier chord
+        let events = [Up(w), Down(q), Up(q)];
+        assert_eq!(vec![vec![Pressed(q)]], grouped(side, &events));
+
+        // one group per key, more groups than PRESS_SIZE
+        let keys: std::vec::Vec<Key> = (0..80u8)
+            .map(|n| Key::from(Event::from((n % 40) | ((n / 40) << 6))))
+            .collect();
+        let mut events: std::vec::Vec<Event> = keys.iter().map(|key| Down(*key)).collect();
+        events.extend(keys.iter().map(|key| Up(*key)));
+        let per_key = grouped(|key| u8::from(Event::Up(key)) as usize, &events);
+        assert_eq!(80, per_key.len());
+    }
+
+    fn stream_tap(
+        stack: &mut Vec<Event, STACK_SIZE>,
+        state: &mut Stream,
+        key: Key,
+    ) -> Vec<Pressed, PRESS_SIZE> {
+        stack.push(Down(key)).unwrap();
+        let presses = stream(stack, state);
+        stack.push(Up(key)).unwrap();
+        let mut released = stream(stack, state);
+        released.extend(presses);
+        released
+    }
+
+    #[test]
+    fn stream_layer() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut state = Stream::new(Trigger::Release);
+        let spc = Right(KeyId::K16);
+        stack.push(Down(spc)).unwrap();
+        assert!(stream(&mut stack, &mut state).is_empty());
+
+        for key in [Left(KeyId::K1), Left(KeyId::K2), Left(KeyId::K3)] {
+            let presses = stream_tap(&mut stack, &mut state, key);
+            assert_eq!(&[Pressed(spc), Pressed(key)], presses.as_slice());
+        }
+
+        // the layer key itself was used, no tap when released
+        stack.push(Up(spc)).unwrap();
+        assert!(stream(&mut stack, &mut state).is_empty());
+        assert!(stack.is_empty());
+
+        // a plain tap afterwards still works
+        let presses = stream_tap(&mut stack, &mut state, spc);
+        assert_eq!(&[Pressed(spc)], presses.as_slice());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn stream_roll_is_not_a_chord() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut state = Stream::new(Trigger::Release);
+        stack.push(Down(Left(KeyId::K1))).unwrap();
+        stack.push(Down(Left(KeyId::K2))).unwrap();
+        stack.push(Up(Left(KeyId::K1))).unwrap();
+
+        let presses = stream(&mut stack, &mut state);
+        assert_eq!(&[Pressed(Left(KeyId::K1))], presses.as_slice());
+
+        stack.push(Up(Left(KeyId::K2))).unwrap();
+        let presses = stream(&mut stack, &mut state);
+        assert_eq!(&[Pressed(Left(KeyId::K2))], presses.as_slice());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn stream_on_press() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut state = Stream::new(Trigger::Press);
+        let spc = Right(KeyId::K16);
+        stack.push(Down(spc)).unwrap();
+        assert!(stream(&mut stack, &mut state).is_empty());
+
+        stack.push(Down(Left(KeyId::K1))).unwrap();
+        let presses = stream(&mut stack, &mut state);
+        assert_eq!(
+            &[Pressed(spc), Pressed(Left(KeyId::K1))],
+            presses.as_slice()
+        );
+        assert_eq!(&[Down(spc)], stack.as_slice());
+
+        // released after the layer key, still swallowed
+        stack.push(Up(spc)).unwrap();
+        assert!(stream(&mut stack, &mut state).is_empty());
+        stack.push(Up(Left(KeyId::K1))).unwrap();
+        assert!(stream(&mut stack, &mut state).is_empty());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn stream_chord_without_inner_tap() {
+        // batched events of a root that never streamed behave like chord()
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut state = Stream::new(Trigger::Release);
+        stack.push(Down(Left(KeyId::K1))).unwrap();
+        stack.push(Down(Left(KeyId::K2))).unwrap();
+        stack.push(Up(Left(KeyId::K1))).unwrap();
+        stack.push(Up(Left(KeyId::K2))).unwrap();
+
+        let presses = stream(&mut stack, &mut state);
+        assert_eq!(
+            &[Pressed(Left(KeyId::K1)), Pressed(Left(KeyId::K2))],
+            presses.as_slice()
+        );
+        assert!(stack.is_empty());
+    }
+
     #[rustfmt::skip]
     #[allow(clippy::unusual_byte_groupings)]
     #[test]
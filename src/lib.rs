@@ -1,11 +1,28 @@
+pub mod autorepeat;
+pub mod autoshift;
+pub mod behavior;
+pub mod debounce;
+pub mod engine;
+pub mod leader;
 pub mod lex;
 pub mod parse;
+pub mod pipeline;
 pub mod report;
+pub mod scan;
+pub mod swaphands;
+pub mod tapdance;
+pub mod watchdog;
 
 #[allow(clippy::crate_in_macro_def)]
 #[macro_export]
 macro_rules! chord {
     ($rule:ident, $len:expr, $chord_event:expr, $emit:expr) => {
+        $crate::chord!($rule, $len, $chord_event, $emit, Prefix, 0);
+    };
+    ($rule:ident, $len:expr, $chord_event:expr, $emit:expr, $mode:ident) => {
+        $crate::chord!($rule, $len, $chord_event, $emit, $mode, 0);
+    };
+    ($rule:ident, $len:expr, $chord_event:expr, $emit:expr, $mode:ident, $priority:expr) => {
         #[allow(non_snake_case)]
         mod $rule {
             use crate::config::*;
@@ -19,7 +36,9 @@ macro_rules! chord {
             pub const CHORD_EMIT: Emit<Keyb> = $emit;
         }
         pub const $rule: $crate::parse::ChordEmit<usbd_human_interface_device::page::Keyboard> =
-            $crate::parse::ChordEmit(&$rule::CHORD_EVENTS, $rule::CHORD_EMIT);
+            $crate::parse::ChordEmit::new(&$rule::CHORD_EVENTS, $rule::CHORD_EMIT)
+                .mode($crate::parse::Match::$mode)
+                .priority($priority);
     }; /*($rule:ident, $chord_event:expr, $emit:expr, $layout:ident) => {
            #[allow(non_snake_case)]
            mod $rule {
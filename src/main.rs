@@ -1,68 +1,51 @@
-use heapless::Vec;
+use std::time::Instant;
+
 use k_board::{keyboard::Keyboard, keys::Keys};
 use tastlib::{
-    lex::{Event, Key, KeyId, STACK_SIZE},
-    report::eval,
+    autoshift::AutoShift,
+    engine::Engine,
+    lex::{Event, Key, KeyId, Millis, REPORT_SIZE, STACK_SIZE},
+    parse::Rules,
+    pipeline::Stage,
 };
 
 mod config;
 
 fn main() {
-    let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
-
-    let mut tab_toggle = false;
-    let mut bck_toggle = false;
-    let mut spc_toggle = false;
-    let mut ret_toggle = false;
+    let rules = Rules::ordered(&config::RULES).overrides(&config::OVERRIDES);
+    let mut engine = Engine::<STACK_SIZE, REPORT_SIZE>::new(rules)
+        .alternates(&config::ALTERNATES)
+        .pipeline(AutoShift::new(200).skip(&config::NO_AUTO_SHIFT));
+    let start = Instant::now();
 
     for key in Keyboard::new() {
+        engine.tick(start.elapsed().as_millis() as Millis);
         match key {
-            Keys::Char(chr) => {
-                if stack.push(from_char_to_event(chr)).is_err() {
-                    panic!("Should have enough capacity to push on stack");
-                }
-            }
-            Keys::Delete => stack.clear(),
-            Keys::Home => tab_sim(&mut tab_toggle, &mut stack),
-            Keys::End => bck_sim(&mut bck_toggle, &mut stack),
-            Keys::Space => ret_sim(&mut ret_toggle, &mut stack),
-            Keys::Enter => spc_sim(&mut spc_toggle, &mut stack),
+            Keys::Char(chr) => engine.feed(from_char_to_event(chr)),
+            Keys::Delete => engine.clear(),
+            Keys::Home => sim(Key::Left(KeyId::K16), &mut engine), // TAB
+            Keys::End => sim(Key::Left(KeyId::K17), &mut engine),  // BCK
+            Keys::Space => sim(Key::Right(KeyId::K17), &mut engine), // RET
+            Keys::Enter => sim(Key::Right(KeyId::K16), &mut engine), // SPC
             Keys::Escape => {
                 break;
             }
             _ => {}
         }
-        let keyboard = eval(&mut stack, &config::RULES);
+        let keyboard = engine.drain_reports();
         if !keyboard.is_empty() {
             println!("Keyboard: {:?}", keyboard);
         }
     }
 }
 
-fn sim(key: Key, toggler: &mut bool, stack: &mut Vec<Event, 128>) {
-    let evt = if *toggler {
-        Event::Up(key)
+// Thumb keys have no char of their own, each press toggles them
+fn sim<P: Stage>(key: Key, engine: &mut Engine<STACK_SIZE, REPORT_SIZE, P>) {
+    if engine.is_held(key) {
+        engine.feed(Event::Up(key));
     } else {
-        Event::Down(key)
-    };
-    *toggler = !*toggler;
-    stack.push(evt).unwrap();
-}
-
-fn spc_sim(toggler: &mut bool, stack: &mut Vec<Event, 128>) {
-    sim(Key::Right(KeyId::K16), toggler, stack);
-}
-
-fn ret_sim(toggler: &mut bool, stack: &mut Vec<Event, 128>) {
-    sim(Key::Right(KeyId::K17), toggler, stack);
-}
-
-fn bck_sim(toggler: &mut bool, stack: &mut Vec<Event, 128>) {
-    sim(Key::Left(KeyId::K17), toggler, stack);
-}
-
-fn tab_sim(toggler: &mut bool, stack: &mut Vec<Event, 128>) {
-    sim(Key::Left(KeyId::K16), toggler, stack);
+        engine.feed(Event::Down(key));
+    }
 }
 
 #[rustfmt::skip]
@@ -93,7 +76,10 @@ fn from_char_to_event(value: char) -> Event {
 
 #[cfg(test)]
 mod tests {
+    use heapless::Vec;
+    use tastlib::autoshift::AutoShift;
     use tastlib::lex::qwerty::*;
+    use tastlib::report::{eval, eval_with, Fallback};
     use usbd_human_interface_device::page::Keyboard as Keyb;
 
     use super::Event::*;
@@ -152,4 +138,420 @@ mod tests {
         let keyboard = eval(&mut stack, &config::RULES);
         assert_eq!(Keyb::Tab, keyboard[0]);
     }
+
+    #[test]
+    fn test_allmod_any_order() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        for key in [R_C, R_A, R_S, A] {
+            stack.push(Down(key.into())).unwrap();
+        }
+        for key in [A, R_S, R_A, R_C] {
+            stack.push(Up(key.into())).unwrap();
+        }
+
+        let keyboard = eval(&mut stack, &config::RULES);
+        assert_eq!(Keyb::RightControl, keyboard[0]);
+        assert_eq!(Keyb::RightAlt, keyboard[1]);
+        assert_eq!(Keyb::RightShift, keyboard[2]);
+        assert_eq!(Keyb::A, keyboard[3]);
+    }
+
+    #[test]
+    fn test_specificity_agrees_with_order() {
+        use tastlib::lex::{Pressed, PRESS_SIZE};
+        use tastlib::parse::{parse_with, Rules};
+
+        let rules = Rules::by_specificity(&config::RULES).unwrap();
+        let keys = (0..40u8)
+            .flat_map(|id| [id, 0b0100_0000 | id])
+            .map(|bits| Pressed(Event::from(bits).into()));
+        for first in keys.clone() {
+            let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+            chord.push(first).unwrap();
+            assert_eq!(parse_with(&chord, &config::RULES), rules.parse(&chord));
+            for second in keys.clone().filter(|k| *k != first) {
+                chord.truncate(1);
+                chord.push(second).unwrap();
+                assert_eq!(parse_with(&chord, &config::RULES), rules.parse(&chord));
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_paste_one_hold() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(R_C.into())).unwrap();
+        stack.push(Down(C.into())).unwrap();
+        stack.push(Up(C.into())).unwrap();
+        stack.push(Down(V.into())).unwrap();
+        stack.push(Up(V.into())).unwrap();
+        stack.push(Up(R_C.into())).unwrap();
+
+        let keyboard = eval(&mut stack, &config::RULES);
+        assert_eq!(
+            &[
+                Keyb::RightControl,
+                Keyb::C,
+                Keyb::Out,
+                Keyb::RightControl,
+                Keyb::V
+            ],
+            keyboard.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_layer_one_hold() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(SPC.into())).unwrap();
+        for key in [Q, W, E] {
+            stack.push(Down(key.into())).unwrap();
+            stack.push(Up(key.into())).unwrap();
+        }
+        stack.push(Up(SPC.into())).unwrap();
+
+        let keyboard = eval(&mut stack, &config::RULES);
+        assert_eq!(
+            &[
+                Keyb::Keyboard1,
+                Keyb::Out,
+                Keyb::Keyboard2,
+                Keyb::Out,
+                Keyb::Keyboard3
+            ],
+            keyboard.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_two_mods_one_hold() {
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(R_C.into())).unwrap();
+        stack.push(Down(R_S.into())).unwrap();
+        for key in [Z, Z] {
+            stack.push(Down(key.into())).unwrap();
+            stack.push(Up(key.into())).unwrap();
+        }
+        stack.push(Up(R_S.into())).unwrap();
+        stack.push(Up(R_C.into())).unwrap();
+
+        let keyboard = eval(&mut stack, &config::RULES);
+        assert_eq!(
+            &[
+                Keyb::RightControl,
+                Keyb::RightShift,
+                Keyb::Z,
+                Keyb::Out,
+                Keyb::RightControl,
+                Keyb::RightShift,
+                Keyb::Z
+            ],
+            keyboard.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_layer_streaming() {
+        use tastlib::lex::{Stream, Trigger};
+        use tastlib::parse::Rules;
+        use tastlib::report::eval_stream;
+
+        let rules = Rules::ordered(&config::RULES);
+        let mut state = Stream::new(Trigger::Release);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(SPC.into())).unwrap();
+        assert!(eval_stream(&mut stack, &rules, &mut state).is_empty());
+        for (key, code) in [
+            (Q, Keyb::Keyboard1),
+            (W, Keyb::Keyboard2),
+            (E, Keyb::Keyboard3),
+        ] {
+            stack.push(Down(key.into())).unwrap();
+            assert!(eval_stream(&mut stack, &rules, &mut state).is_empty());
+            stack.push(Up(key.into())).unwrap();
+            let keyboard = eval_stream(&mut stack, &rules, &mut state);
+            assert_eq!(&[code], keyboard.as_slice());
+        }
+        stack.push(Up(SPC.into())).unwrap();
+        assert!(eval_stream(&mut stack, &rules, &mut state).is_empty());
+
+        stack.push(Down(SPC.into())).unwrap();
+        stack.push(Up(SPC.into())).unwrap();
+        let keyboard = eval_stream(&mut stack, &rules, &mut state);
+        assert_eq!(&[Keyb::Space], keyboard.as_slice());
+    }
+
+    #[test]
+    fn test_early_emission() {
+        use tastlib::parse::Rules;
+        use tastlib::report::{eval_early, Early};
+
+        let rules = Rules::ordered(&config::RULES);
+        let mut state = Early::new(&rules);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        // Q roots no multi-key rule, it goes out on press
+        stack.push(Down(Q.into())).unwrap();
+        let keyboard = eval_early(&mut stack, &rules, &mut state);
+        assert_eq!(&[Keyb::Q], keyboard.as_slice());
+        stack.push(Up(Q.into())).unwrap();
+        assert!(eval_early(&mut stack, &rules, &mut state).is_empty());
+
+        // A is a home row modifier, it still waits for the chord
+        stack.push(Down(A.into())).unwrap();
+        assert!(eval_early(&mut stack, &rules, &mut state).is_empty());
+        stack.push(Up(A.into())).unwrap();
+        let keyboard = eval_early(&mut stack, &rules, &mut state);
+        assert_eq!(&[Keyb::A], keyboard.as_slice());
+    }
+
+    #[test]
+    fn test_early_same_as_waiting() {
+        use tastlib::lex::Pressed;
+        use tastlib::parse::Rules;
+        use tastlib::report::{eval_by, eval_early, Early};
+
+        let rules = Rules::ordered(&config::RULES);
+        let keys = [Q, W, E, A, S, J, K, SPC, TAB, RET];
+        let mut state = Early::new(&rules);
+        let mut seed: u32 = 0x2545_f491;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as usize % n
+        };
+        for _ in 0..500 {
+            let mut events: std::vec::Vec<Event> = std::vec::Vec::new();
+            let mut held: std::vec::Vec<Pressed> = std::vec::Vec::new();
+            for _ in 0..12 {
+                let key = keys[random(keys.len())];
+                if let Some(ix) = held.iter().position(|k| *k == key) {
+                    held.remove(ix);
+                    events.push(Up(key.into()));
+                } else if held.len() < 4 {
+                    held.push(key);
+                    events.push(Down(key.into()));
+                }
+            }
+            events.extend(held.iter().map(|key| Up((*key).into())));
+
+            let mut waiting: Vec<Event, STACK_SIZE> = Vec::new();
+            let mut early: Vec<Event, STACK_SIZE> = Vec::new();
+            let (mut expected, mut actual) = (vec![Keyb::Out], vec![Keyb::Out]);
+            for event in &events {
+                waiting.push(*event).unwrap();
+                expected.extend(eval_by(&mut waiting, &rules));
+                expected.push(Keyb::Out);
+                early.push(*event).unwrap();
+                actual.extend(eval_early(&mut early, &rules, &mut state));
+                actual.push(Keyb::Out);
+            }
+            expected.dedup_by(|a, b| *a == Keyb::Out && *b == Keyb::Out);
+            actual.dedup_by(|a, b| *a == Keyb::Out && *b == Keyb::Out);
+            assert_eq!(expected, actual, "{:?}", events);
+        }
+    }
+
+    #[test]
+    fn test_engine_thumb_toggle() {
+        let mut engine: Engine<STACK_SIZE, REPORT_SIZE> =
+            Engine::new(Rules::ordered(&config::RULES));
+        let spc = Key::Right(KeyId::K16);
+        sim(spc, &mut engine);
+        assert!(engine.is_held(spc));
+        engine.feed(Down(Q.into()));
+        engine.feed(Up(Q.into()));
+        sim(spc, &mut engine);
+        assert!(!engine.is_held(spc));
+        assert_eq!(&[Keyb::Keyboard1], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn test_auto_shift_home_row() {
+        let mut engine = Engine::<STACK_SIZE, REPORT_SIZE>::new(Rules::ordered(&config::RULES))
+            .pipeline(AutoShift::new(200).skip(&config::NO_AUTO_SHIFT));
+        // a home row mod held alone stays a letter
+        engine.feed(Down(L_S.into()));
+        engine.tick(300);
+        engine.feed(Up(L_S.into()));
+        assert_eq!(&[Keyb::D], engine.drain_reports().as_slice());
+        // and still shifts the key tapped under it
+        engine.feed(Down(L_S.into()));
+        engine.feed(Down(J.into()));
+        engine.feed(Up(J.into()));
+        engine.tick(600);
+        engine.feed(Up(L_S.into()));
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::J],
+            engine.drain_reports().as_slice()
+        );
+        engine.feed(Down(Q.into()));
+        engine.tick(900);
+        engine.feed(Up(Q.into()));
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let rules = Rules::ordered(&config::RULES).overrides(&config::OVERRIDES);
+        let mut engine: Engine<STACK_SIZE, REPORT_SIZE> = Engine::new(rules);
+        // right home row shift on the backspace thumb
+        for event in [
+            Down(R_S.into()),
+            Down(BCK.into()),
+            Up(BCK.into()),
+            Up(R_S.into()),
+        ] {
+            engine.feed(event);
+        }
+        assert_eq!(&[Keyb::DeleteForward], engine.drain_reports().as_slice());
+        // RET+F types < as shifted comma
+        for event in [
+            Down(RET.into()),
+            Down(F.into()),
+            Up(F.into()),
+            Up(RET.into()),
+        ] {
+            engine.feed(event);
+        }
+        assert_eq!(
+            &[Keyb::RightShift, Keyb::Comma],
+            engine.drain_reports().as_slice()
+        );
+        engine.feed(Down(BCK.into()));
+        engine.feed(Up(BCK.into()));
+        assert_eq!(&[Keyb::DeleteBackspace], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn test_overrides_after_auto_shift() {
+        use tastlib::parse::{Emit::Code, Override};
+
+        // Shift+, is ; rather than <
+        const OVERRIDES: [Override<Keyb>; 1] = [Override::new(
+            &[Keyb::LeftShift],
+            Keyb::Comma,
+            Code(Keyb::Semicolon),
+        )];
+        let rules = Rules::ordered(&config::RULES).overrides(&OVERRIDES);
+        let mut engine = Engine::<STACK_SIZE, REPORT_SIZE>::new(rules)
+            .pipeline(AutoShift::new(200).skip(&config::NO_AUTO_SHIFT));
+        engine.feed(Down(COMMA.into()));
+        engine.tick(300);
+        engine.feed(Up(COMMA.into()));
+        assert_eq!(&[Keyb::Semicolon], engine.drain_reports().as_slice());
+        // thumb keys are not auto shifted
+        engine.feed(Down(BCK.into()));
+        engine.tick(600);
+        engine.feed(Up(BCK.into()));
+        assert_eq!(&[Keyb::DeleteBackspace], engine.drain_reports().as_slice());
+    }
+
+    #[test]
+    fn test_repeat_keys() {
+        use tastlib::lex::Pressed;
+
+        let mut engine: Engine<STACK_SIZE, REPORT_SIZE> =
+            Engine::new(Rules::ordered(&config::RULES)).alternates(&config::ALTERNATES);
+        let both = |engine: &mut Engine<STACK_SIZE, REPORT_SIZE>, a: Pressed, b: Pressed| {
+            for event in [Down(a.into()), Down(b.into()), Up(b.into()), Up(a.into())] {
+                engine.feed(event);
+            }
+        };
+        // ) from the symbol layer, repeated, then its counterpart
+        both(&mut engine, RET, P);
+        both(&mut engine, BCK, RET);
+        both(&mut engine, BCK, SPC);
+        assert_eq!(
+            &[
+                Keyb::RightShift,
+                Keyb::Keyboard0,
+                Keyb::Out,
+                Keyb::RightShift,
+                Keyb::Keyboard0,
+                Keyb::Out,
+                Keyb::LeftShift,
+                Keyb::Keyboard9
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_repeat_after_auto_shift() {
+        let mut engine = Engine::<STACK_SIZE, REPORT_SIZE>::new(Rules::ordered(&config::RULES))
+            .pipeline(AutoShift::new(200).skip(&config::NO_AUTO_SHIFT));
+        engine.feed(Down(Q.into()));
+        engine.tick(300);
+        engine.feed(Up(Q.into()));
+        for event in [
+            Down(BCK.into()),
+            Down(RET.into()),
+            Up(RET.into()),
+            Up(BCK.into()),
+        ] {
+            engine.feed(event);
+        }
+        // the repeat is of the report as sent
+        assert_eq!(
+            &[
+                Keyb::LeftShift,
+                Keyb::Q,
+                Keyb::Out,
+                Keyb::LeftShift,
+                Keyb::Q
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_both_is_the_exact_pair() {
+        // TAB_SPC_ESC is the exact pair TAB+SPC, TAB+RET is a shifted RET
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        for event in [
+            Down(TAB.into()),
+            Down(RET.into()),
+            Up(RET.into()),
+            Up(TAB.into()),
+        ] {
+            stack.push(event).unwrap();
+        }
+        assert_eq!(
+            &[Keyb::LeftShift, Keyb::ReturnEnter],
+            eval(&mut stack, &config::RULES).as_slice()
+        );
+    }
+
+    #[test]
+    fn test_long_tap_run_under_layer() {
+        // one report per tap, those past the report buffer are dropped
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        stack.push(Down(SPC.into())).unwrap();
+        for _ in 0..17 {
+            stack.push(Down(Q.into())).unwrap();
+            stack.push(Up(Q.into())).unwrap();
+        }
+        stack.push(Up(SPC.into())).unwrap();
+        let reports = eval(&mut stack, &config::RULES);
+        assert_eq!(
+            16,
+            reports.iter().filter(|c| **c == Keyb::Keyboard1).count()
+        );
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_replay_all() {
+        // ON_Q only uses Q, W rolled in under it is no part of any rule
+        let rules = Rules::ordered(&config::RULES);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut reports = std::vec::Vec::new();
+        for event in [Down(Q.into()), Down(W.into()), Up(W.into()), Up(Q.into())] {
+            stack.push(event).unwrap();
+            reports.extend(eval_with(&mut stack, &rules, Fallback::ReplayAll));
+        }
+        assert_eq!(&[Keyb::Q, Keyb::Out, Keyb::W], reports.as_slice());
+    }
 }
@@ -1,10 +1,19 @@
-use crate::lex::{Key, Pressed, PRESS_SIZE};
+use crate::behavior::Behavior;
+use crate::lex::{bit, Event, Key, Pressed, PRESS_SIZE};
 use heapless::Vec;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ChordEvent {
+    // Two keys in a row where either one is of the pair, All(&[a, b]) is the
+    // exact pair. Unordered rules need both keys of the pair
     Both(Pressed, Pressed),
+    All(&'static [Pressed]),
     On(Pressed),
+    // Any key in a user defined group, e.g. the thumb keys
+    In(&'static [Pressed]),
+    // Not, OneOf and In only ever look at a single key
+    Not(&'static ChordEvent),
+    OneOf(&'static [ChordEvent]),
     Optional(&'static ChordEvent),
     RAny,
     LAny,
@@ -20,77 +29,520 @@ pub enum Emit<T: 'static + std::marker::Copy> {
     String(&'static str),
     Code(T),
     Identity,
+    // Dispatches to a user defined action, see Behavior. Modifiers around it
+    // are dropped, a behavior sends its own reports
+    Custom(&'static dyn Behavior),
+    // The last report again, modifiers included
+    Repeat,
+    // The counterpart of the last report, see Engine::alternates
+    AltRepeat,
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Match {
+    // Rule events match the start of the chord, any keys after that are ignored
+    #[default]
+    Prefix,
+    // Rule events match the whole chord, in order, trailing Optional events
+    // may be left without a key
+    Exact,
+    // Rule events match the whole chord, in any order
+    Unordered,
+}
+
+// Built with new, mode and priority. The last field is an explicit priority,
+// only used to break ties in Priority::Specificity
 #[derive(Debug)]
-pub struct ChordEmit<T: 'static + std::marker::Copy>(pub &'static [ChordEvent], pub Emit<T>);
+pub struct ChordEmit<T: 'static + std::marker::Copy>(
+    pub &'static [ChordEvent],
+    pub Emit<T>,
+    Match,
+    u8,
+);
+
+impl<T: 'static + std::marker::Copy> ChordEmit<T> {
+    pub const fn new(events: &'static [ChordEvent], emit: Emit<T>) -> Self {
+        ChordEmit(events, emit, Match::Prefix, 0)
+    }
+
+    pub const fn mode(self, mode: Match) -> Self {
+        ChordEmit(self.0, self.1, mode, self.3)
+    }
+
+    pub const fn priority(self, priority: u8) -> Self {
+        ChordEmit(self.0, self.1, self.2, priority)
+    }
+
+    // Number of leading chord keys held as context (modifiers, layers) for
+    // the last rule event, None if the last event does not stand for one tapped key
+    pub fn context(&self, chord: &[Pressed]) -> Option<usize> {
+        let (last, held) = self.0.split_last()?;
+        match (self.2, last) {
+            (Match::Unordered, _)
+            | (_, ChordEvent::Both(_, _) | ChordEvent::All(_) | ChordEvent::Optional(_)) => None,
+            _ => consume(chord, held, self.2 == Match::Exact),
+        }
+    }
+
+    // Number of chord keys the rule stands for, a Prefix rule may leave
+    // keys after it unused
+    pub fn consumed(&self, chord: &[Pressed]) -> usize {
+        match self.2 {
+            Match::Prefix => consume(chord, self.0, false).unwrap_or(0),
+            Match::Exact | Match::Unordered => chord.len(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Priority {
+    // First matching rule in the table wins, NB: order matters
+    #[default]
+    Order,
+    // Most specific matching rule wins, regardless of order
+    Specificity,
+}
+
+// Indices of two rules that are equally specific, see Rules::by_specificity
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Ambiguous {
+    // Both rules match the same chord
+    Overlap(usize, usize),
+    // The rules are too large to check within SEARCH_LIMIT, they may overlap
+    Unchecked(usize, usize),
+}
+
+// While all mods are in a report, key sends emit instead, e.g. Shift+Backspace
+// as Delete. Mods match either side and are dropped from the report unless kept
+#[derive(Debug, Copy, Clone)]
+pub struct Override<T: 'static + std::marker::Copy> {
+    pub mods: &'static [T],
+    pub key: T,
+    pub emit: Emit<T>,
+    pub keep: bool,
+}
+
+impl<T: 'static + std::marker::Copy> Override<T> {
+    pub const fn new(mods: &'static [T], key: T, emit: Emit<T>) -> Self {
+        Override {
+            mods,
+            key,
+            emit,
+            keep: false,
+        }
+    }
+
+    pub const fn keep(self) -> Self {
+        Override { keep: true, ..self }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Rules<'a, T: 'static + std::marker::Copy> {
+    table: &'a [ChordEmit<T>],
+    priority: Priority,
+    pub(crate) overrides: &'a [Override<T>],
+}
+
+impl<'a, T: 'static + std::marker::Copy> Rules<'a, T> {
+    pub fn ordered(table: &'a [ChordEmit<T>]) -> Self {
+        Rules {
+            table,
+            priority: Priority::Order,
+            overrides: &[],
+        }
+    }
+
+    // Fails on the first two equally specific rules that can match the same
+    // chord, or that are too large to check. An explicit priority tells
+    // either apart
+    pub fn by_specificity(table: &'a [ChordEmit<T>]) -> Result<Self, Ambiguous> {
+        for (i, a) in table.iter().enumerate() {
+            for (j, b) in table.iter().enumerate().skip(i + 1) {
+                if specificity(a) != specificity(b) {
+                    continue;
+                }
+                match overlap(a, b) {
+                    Some(false) => {}
+                    Some(true) => return Err(Ambiguous::Overlap(i, j)),
+                    None => return Err(Ambiguous::Unchecked(i, j)),
+                }
+            }
+        }
+        Ok(Rules {
+            table,
+            priority: Priority::Specificity,
+            overrides: &[],
+        })
+    }
+
+    // Checked in order, the first override that applies wins
+    pub fn overrides(self, overrides: &'a [Override<T>]) -> Self {
+        Rules { overrides, ..self }
+    }
+
+    pub fn parse(&self, chord: &[Pressed]) -> Emit<T> {
+        self.find(chord).map_or(Emit::Identity, |rule| rule.1)
+    }
+
+    // True when no further key can change what the held keys resolve to, so
+    // emitting them now gives the same output as waiting for the chord. Keys
+    // left unused go by the fallback, Matched and Drop keep the rule
+    pub fn settled(&self, held: &[Pressed]) -> bool {
+        let Some(rule) = self.find(held) else {
+            return false;
+        };
+        if rule.2 != Match::Prefix
+            || rule.context(held).is_some_and(|held| held > 0)
+            // Identity takes the last chord key, which changes with every further key
+            || matches!(leaf(rule.1), Emit::Identity)
+        {
+            return false;
+        }
+        self.table
+            .iter()
+            .all(|rule| rule_match(held, rule.0, rule.2) || !extends(held, rule))
+    }
+
+    // The keys settled on their own, one bit each, see lex::bit. Worked out
+    // once, settled searches the table
+    pub fn settled_keys(&self) -> u128 {
+        let mut settled = 0;
+        for ix in (0..40).chain(0b0100_0000..0b0100_0000 + 40) {
+            let key = Key::from(Event::from(ix));
+            if self.settled(&[Pressed(key)]) {
+                settled |= bit(key);
+            }
+        }
+        settled
+    }
+
+    pub fn find(&self, chord: &[Pressed]) -> Option<&'a ChordEmit<T>> {
+        let mut matching = self
+            .table
+            .iter()
+            .filter(|rule| rule_match(chord, rule.0, rule.2));
+        match self.priority {
+            Priority::Order => matching.next(),
+            Priority::Specificity => matching.max_by_key(|rule| specificity(rule)),
+        }
+    }
+}
+
+// The emit left once the modifiers are peeled off
+pub(crate) fn leaf<T: 'static + std::marker::Copy>(emit: Emit<T>) -> Emit<T> {
+    match emit {
+        Emit::Mod(next) | Emit::Ctrl(next) | Emit::Shift(next) | Emit::Alt(next) => leaf(*next),
+        _ => emit,
+    }
+}
+
+// Compared in order: concrete keys, exact length over prefix, keys required,
+// side or group wildcards over Any, explicit priority
+fn specificity<T: 'static + std::marker::Copy>(
+    rule: &ChordEmit<T>,
+) -> (usize, bool, usize, usize, u8) {
+    let mut concrete = 0;
+    let mut required = 0;
+    let mut narrow = 0;
+    for event in rule.0 {
+        match event {
+            ChordEvent::On(_) => concrete += 1,
+            // one of the two keys is named, the other may be any
+            ChordEvent::Both(_, _) => {
+                concrete += 1;
+                narrow += 1;
+            }
+            ChordEvent::All(set) => concrete += set.len(),
+            ChordEvent::In(_)
+            | ChordEvent::Not(_)
+            | ChordEvent::OneOf(_)
+            | ChordEvent::LAny
+            | ChordEvent::RAny => narrow += 1,
+            ChordEvent::Any | ChordEvent::Optional(_) => {}
+        }
+        required += width(event);
+    }
+    (concrete, rule.2 != Match::Prefix, required, narrow, rule.3)
+}
+
+fn width(event: &ChordEvent) -> usize {
+    match event {
+        ChordEvent::Both(_, _) => 2,
+        ChordEvent::All(set) => set.len(),
+        ChordEvent::Optional(_) => 0,
+        _ => 1,
+    }
+}
+
+// Chords one search tries before it gives up. The number of chords grows
+// exponentially with the rule length, a search that gives up counts as found
+pub const SEARCH_LIMIT: u32 = 10_000;
+
+// Searches for a chord both rules match, built from the keys the rules name
+// plus one unnamed key per side. None if the search gave up
+fn overlap<T: 'static + std::marker::Copy>(a: &ChordEmit<T>, b: &ChordEmit<T>) -> Option<bool> {
+    let pool = pool(a.0.iter().chain(b.0), &[]);
+    let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+    let mut budget = Some(SEARCH_LIMIT);
+    let found = (shortest(a).max(shortest(b))..=longest(a).max(longest(b))).any(|len| {
+        search(&mut chord, &pool, len, &mut budget, &|chord| {
+            rule_match(chord, a.0, a.2) && rule_match(chord, b.0, b.2)
+        })
+    });
+    budget.map(|_| found)
+}
+
+// Searches for a chord starting with the held keys and at least one more key
+// that the rule matches
+fn extends<T: 'static + std::marker::Copy>(held: &[Pressed], rule: &ChordEmit<T>) -> bool {
+    let pool = pool(rule.0.iter(), held);
+    let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+    chord.extend_from_slice(held).unwrap();
+    let mut budget = Some(SEARCH_LIMIT);
+    (held.len() + 1..=longest(rule).max(held.len() + 1)).any(|len| {
+        search(&mut chord, &pool, len, &mut budget, &|chord| {
+            rule_match(chord, rule.0, rule.2)
+        })
+    })
+}
+
+fn longest<T: 'static + std::marker::Copy>(rule: &ChordEmit<T>) -> usize {
+    rule.0
+        .iter()
+        .map(|event| match event {
+            ChordEvent::Optional(opt) => width(opt),
+            _ => width(event),
+        })
+        .sum()
+}
+
+fn shortest<T: 'static + std::marker::Copy>(rule: &ChordEmit<T>) -> usize {
+    rule.0.iter().map(width).sum()
+}
+
+// Keys named by the events plus one unnamed key per side, none of them taken
+fn pool<'e>(events: impl Iterator<Item = &'e ChordEvent>, taken: &[Pressed]) -> Vec<Pressed, 128> {
+    let mut pool: Vec<Pressed, 128> = Vec::new();
+    for event in events {
+        named(*event, &mut pool);
+    }
+    pool.retain(|press| !taken.contains(press));
+    for side in [0b0100_0000, 0] {
+        let unnamed = (0..40)
+            .map(|id| Pressed(Event::from(side | id).into()))
+            .find(|p| !pool.contains(p) && !taken.contains(p));
+        if let Some(press) = unnamed {
+            pool.push(press).unwrap();
+        }
+    }
+    pool
+}
+
+fn named(event: ChordEvent, pool: &mut Vec<Pressed, 128>) {
+    let mut add = |press: &Pressed| {
+        if !pool.contains(press) {
+            pool.push(*press).unwrap();
+        }
+    };
+    match event {
+        ChordEvent::On(press) => add(&press),
+        ChordEvent::Both(p1, p2) => {
+            add(&p1);
+            add(&p2);
+        }
+        ChordEvent::All(set) | ChordEvent::In(set) => set.iter().for_each(add),
+        ChordEvent::Not(event) | ChordEvent::Optional(event) => named(*event, pool),
+        ChordEvent::OneOf(events) => events.iter().for_each(|event| named(*event, pool)),
+        ChordEvent::RAny | ChordEvent::LAny | ChordEvent::Any => {}
+    }
+}
 
-fn rule_match(chord: &Vec<Pressed, PRESS_SIZE>, rule_events: &[ChordEvent]) -> bool {
-    let mut ixoffset: i8 = 0;
+// Grows the chord from the pool up to len keys until found accepts it,
+// the chord is left as it was. A budget used up is None
+fn search(
+    chord: &mut Vec<Pressed, PRESS_SIZE>,
+    pool: &[Pressed],
+    len: usize,
+    budget: &mut Option<u32>,
+    found: &impl Fn(&[Pressed]) -> bool,
+) -> bool {
+    *budget = budget.and_then(|left| left.checked_sub(1));
+    if budget.is_none() {
+        return true;
+    }
+    if chord.len() == len {
+        return found(chord);
+    }
+    for press in pool {
+        if chord.contains(press) {
+            continue;
+        }
+        chord.push(*press).unwrap();
+        let hit = search(chord, pool, len, budget, found);
+        chord.pop();
+        if hit {
+            return true;
+        }
+    }
+    false
+}
+
+fn rule_match(chord: &[Pressed], rule_events: &[ChordEvent], mode: Match) -> bool {
+    match mode {
+        Match::Prefix => consume(chord, rule_events, false).is_some(),
+        Match::Exact => consume(chord, rule_events, true) == Some(chord.len()),
+        Match::Unordered => unordered(chord, rule_events, 0),
+    }
+}
+
+// Number of chord keys used by the rule events, None if they do not match.
+// Every event needs a key of the chord, an Optional too, unless tail lets
+// Optional events past the end of the chord go without
+fn consume(chord: &[Pressed], rule_events: &[ChordEvent], tail: bool) -> Option<usize> {
+    let mut ixoffset: isize = 0;
 
-    //TODO: skip if chord.len != events.len
     for (ix, event) in rule_events.iter().enumerate() {
-        let ix = (ix as i8 + ixoffset) as usize;
+        let ix = (ix as isize + ixoffset) as usize;
         if ix >= chord.len() {
-            return false;
+            if let (true, ChordEvent::Optional(_)) = (tail, event) {
+                ixoffset -= 1;
+                continue;
+            }
+            return None;
         }
         // println!("ix {} chrd {:?} evt {:?}", ix, chord[ix], event);
         match event {
-            ChordEvent::Optional(opt) => {
-                let mut subchord: Vec<Pressed, PRESS_SIZE> = Vec::new();
-                subchord.extend_from_slice(&chord[ix..]).unwrap(); //TODO: fix me
-                let event = *(*opt);
-                if !rule_match(&subchord, &[event]) {
-                    ixoffset -= 1;
-                }
-            }
+            ChordEvent::Optional(opt) => match consume(&chord[ix..], &[**opt], tail) {
+                Some(used) => ixoffset += used as isize - 1,
+                None => ixoffset -= 1,
+            },
             ChordEvent::Any => {
                 // NO OP
             }
             ChordEvent::Both(p1, p2) => {
                 // we need to look ahead when encountering a both scenario
                 if chord.len() < ix + 2 {
-                    return false;
+                    return None;
                 }
                 let ch1 = chord[ix];
                 let ch2 = chord[ix + 1];
                 if ch1 != *p1 && ch2 != *p2 && ch1 != *p2 && ch2 != *p1 {
-                    return false;
+                    return None;
                 }
                 ixoffset += 1;
             }
+            ChordEvent::All(set) => {
+                // any permutation of the set, without repeats, an empty set
+                // matches nothing and the keys of a chord fit a u128
+                if set.is_empty() || set.len() > 128 || chord.len() < ix + set.len() {
+                    return None;
+                }
+                let mut seen: u128 = 0;
+                for press in &chord[ix..ix + set.len()] {
+                    let found = set.iter().position(|p| p == press)?;
+                    if seen & (1 << found) != 0 {
+                        return None;
+                    }
+                    seen |= 1 << found;
+                }
+                ixoffset += set.len() as isize - 1;
+            }
             ChordEvent::On(pressed) => {
                 if *pressed != chord[ix] {
-                    return false;
+                    return None;
+                }
+            }
+            ChordEvent::In(group) => {
+                if !group.contains(&chord[ix]) {
+                    return None;
+                }
+            }
+            ChordEvent::Not(event) => {
+                if key_match(chord[ix], **event) {
+                    return None;
+                }
+            }
+            ChordEvent::OneOf(events) => {
+                if !events.iter().any(|event| key_match(chord[ix], *event)) {
+                    return None;
                 }
             }
             ChordEvent::RAny => {
                 let Pressed(key) = chord[ix];
                 if let Key::Left(_) = key {
-                    return false;
+                    return None;
                 }
             }
             ChordEvent::LAny => {
                 let Pressed(key) = chord[ix];
                 if let Key::Right(_) = key {
-                    return false;
+                    return None;
                 }
             }
         }
     }
-    true
+    Some((rule_events.len() as isize + ixoffset) as usize)
+}
+
+fn key_match(press: Pressed, event: ChordEvent) -> bool {
+    consume(&[press], &[event], false).is_some()
+}
+
+// Backtracking over the chord keys not yet used, one bit per key. A chord of
+// more keys than the mask has bits matches nothing
+fn unordered(chord: &[Pressed], rule_events: &[ChordEvent], used: u128) -> bool {
+    if chord.len() > u128::BITS as usize {
+        return false;
+    }
+    match rule_events.split_first() {
+        None => used.count_ones() as usize == chord.len(),
+        Some((event, rest)) => unordered_take(chord, *event, used, rest),
+    }
+}
+
+fn unordered_take(chord: &[Pressed], event: ChordEvent, used: u128, rest: &[ChordEvent]) -> bool {
+    let free = |ix: &usize| used & (1 << ix) == 0;
+    match event {
+        ChordEvent::Optional(opt) => {
+            unordered_take(chord, *opt, used, rest) || unordered(chord, rest, used)
+        }
+        ChordEvent::Both(p1, p2) => (0..chord.len())
+            .filter(free)
+            .filter(|ix| chord[*ix] == p1)
+            .any(|ix1| {
+                (0..chord.len())
+                    .filter(free)
+                    .filter(|ix| *ix != ix1 && chord[*ix] == p2)
+                    .any(|ix2| unordered(chord, rest, used | 1 << ix1 | 1 << ix2))
+            }),
+        ChordEvent::All([]) => false,
+        ChordEvent::All(set) => {
+            let mut used = used;
+            for press in set {
+                let free = |ix: &usize| used & (1 << ix) == 0;
+                let Some(ix) = (0..chord.len())
+                    .filter(free)
+                    .find(|ix| chord[*ix] == *press)
+                else {
+                    return false;
+                };
+                used |= 1 << ix;
+            }
+            unordered(chord, rest, used)
+        }
+        _ => (0..chord.len())
+            .filter(free)
+            .filter(|ix| key_match(chord[*ix], event))
+            .any(|ix| unordered(chord, rest, used | 1 << ix)),
+    }
 }
 
 pub fn parse_with<T: 'static + std::marker::Copy, const RULE_SIZE: usize>(
     chord: &Vec<Pressed, PRESS_SIZE>,
     rules: &[ChordEmit<T>; RULE_SIZE],
 ) -> Emit<T> {
-    for rule in rules {
-        if rule_match(chord, rule.0) {
-            return rule.1;
-        }
-    }
-    Emit::Identity
+    Rules::ordered(rules).parse(chord)
 }
 
 #[cfg(test)]
@@ -110,18 +562,19 @@ mod tests {
 
     // chord!(SHIFT_L, 2, [On(D), RAny], Shift(&Identity));
     const SHIFT_L_EVENTS: [ChordEvent; 2] = [On(D), RAny];
-    const SHIFT_L: ChordEmit<Keyboard> = ChordEmit(&SHIFT_L_EVENTS, Shift(&Identity));
+    const SHIFT_L: ChordEmit<Keyboard> = ChordEmit::new(&SHIFT_L_EVENTS, Shift(&Identity));
 
     const CONTROL_SHIFT_R_EVENTS: [ChordEvent; 2] = [Both(H, J), LAny];
     const CONTROL_SHIFT_R: ChordEmit<Keyboard> =
-        ChordEmit(&CONTROL_SHIFT_R_EVENTS, Ctrl(&Shift(&Identity)));
+        ChordEmit::new(&CONTROL_SHIFT_R_EVENTS, Ctrl(&Shift(&Identity)));
 
     const Q_CODE_EVENTS: [ChordEvent; 2] = [Both(H, J), On(Q)];
-    const Q_CODE: ChordEmit<Keyboard> = ChordEmit(&Q_CODE_EVENTS, Ctrl(&Shift(&Code(Keyboard::A))));
+    const Q_CODE: ChordEmit<Keyboard> =
+        ChordEmit::new(&Q_CODE_EVENTS, Ctrl(&Shift(&Code(Keyboard::A))));
 
     const W_STRING_EVENTS: [ChordEvent; 2] = [Both(H, J), On(W)];
     const W_STRING: ChordEmit<Keyboard> =
-        ChordEmit(&W_STRING_EVENTS, Ctrl(&Shift(&String("Hello World"))));
+        ChordEmit::new(&W_STRING_EVENTS, Ctrl(&Shift(&String("Hello World"))));
 
     const OPT_CTRL_R1_EVENTS: [ChordEvent; 3] = [
         Optional(&On(Pressed(Key::Left(KeyId::K16)))),
@@ -129,11 +582,21 @@ mod tests {
         On(Pressed(Key::Right(KeyId::K1))),
     ];
     const OPT_CTRL_R1: ChordEmit<Keyboard> =
-        ChordEmit(&OPT_CTRL_R1_EVENTS, Ctrl(&String("Optional")));
+        ChordEmit::new(&OPT_CTRL_R1_EVENTS, Ctrl(&String("Optional")));
+
+    const ALL_MOD_EVENTS: [ChordEvent; 2] = [All(&[A, S, D]), RAny];
+    const ALL_MOD: ChordEmit<Keyboard> =
+        ChordEmit::new(&ALL_MOD_EVENTS, Ctrl(&Alt(&Shift(&Identity))));
 
     // NB: order matters
-    const RULES: [ChordEmit<Keyboard>; 5] =
-        [OPT_CTRL_R1, Q_CODE, W_STRING, CONTROL_SHIFT_R, SHIFT_L];
+    const RULES: [ChordEmit<Keyboard>; 6] = [
+        OPT_CTRL_R1,
+        Q_CODE,
+        W_STRING,
+        CONTROL_SHIFT_R,
+        ALL_MOD,
+        SHIFT_L,
+    ];
 
     #[test]
     fn single_key() {
@@ -206,4 +669,372 @@ mod tests {
         let emit = parse_with(&chord, &RULES);
         assert_eq!(Identity, emit);
     }
+
+    #[test]
+    fn all_chord() {
+        let permutations = [
+            [A, S, D],
+            [A, D, S],
+            [S, A, D],
+            [S, D, A],
+            [D, A, S],
+            [D, S, A],
+        ];
+        for permutation in permutations {
+            let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+            chord.extend_from_slice(&permutation).unwrap();
+            chord.push(J).unwrap();
+
+            let emit = parse_with(&chord, &RULES);
+            assert_eq!(Ctrl(&Alt(&Shift(&Identity))), emit);
+        }
+    }
+
+    #[test]
+    fn all_chord_fail() {
+        let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+        // repeated key
+        chord.extend_from_slice(&[A, S, S, J]).unwrap();
+        assert_eq!(Identity, parse_with(&chord, &RULES));
+
+        // missing key
+        chord.clear();
+        chord.extend_from_slice(&[A, S, J]).unwrap();
+        assert_eq!(Identity, parse_with(&chord, &RULES));
+
+        // too short
+        chord.clear();
+        chord.extend_from_slice(&[S, A]).unwrap();
+        assert_eq!(Identity, parse_with(&chord, &RULES));
+    }
+
+    #[test]
+    fn all_set_sizes() {
+        const NONE: [ChordEvent; 2] = [All(&[]), Any];
+        assert_eq!(None, consume(&[A, S], &NONE, false));
+        assert!(!unordered(&[A], &NONE, 0));
+
+        // every key of both halves, more than a u64 could track
+        let keys: std::vec::Vec<Pressed> = (0..128u8)
+            .filter(|bits| bits & 0b0011_1111 < 40)
+            .map(|bits| Pressed(Event::from(bits).into()))
+            .collect();
+        let set: &'static [Pressed] = std::vec::Vec::leak(keys.clone());
+        let events = [All(set)];
+        let reversed: std::vec::Vec<Pressed> = keys.iter().rev().copied().collect();
+        assert_eq!(Some(80), consume(&reversed, &events, false));
+        assert_eq!(None, consume(&reversed[1..], &events, false));
+    }
+
+    const L_THUMB: Pressed = Pressed(Key::Left(KeyId::K16));
+    const R_THUMB: Pressed = Pressed(Key::Right(KeyId::K16));
+    const THUMBS: [Pressed; 2] = [L_THUMB, R_THUMB];
+    const HOME_ROW: [Pressed; 8] = [A, S, D, F, J, K, L, SEMICOLON];
+
+    const THUMB_HOME_EVENTS: [ChordEvent; 2] = [In(&THUMBS), In(&HOME_ROW)];
+    const THUMB_HOME: ChordEmit<Keyboard> = ChordEmit::new(&THUMB_HOME_EVENTS, Code(Keyboard::A));
+
+    const THUMB_NOT_HOME_EVENTS: [ChordEvent; 2] = [On(L_THUMB), Not(&In(&HOME_ROW))];
+    const THUMB_NOT_HOME: ChordEmit<Keyboard> =
+        ChordEmit::new(&THUMB_NOT_HOME_EVENTS, Code(Keyboard::B));
+
+    const NON_THUMB_RIGHT_EVENTS: [ChordEvent; 2] =
+        [On(R_THUMB), Not(&OneOf(&[In(&THUMBS), LAny]))];
+    const NON_THUMB_RIGHT: ChordEmit<Keyboard> =
+        ChordEmit::new(&NON_THUMB_RIGHT_EVENTS, Shift(&Identity));
+
+    const ONE_OF_EVENTS: [ChordEvent; 2] = [OneOf(&[On(Q), On(P)]), On(R_THUMB)];
+    const ONE_OF: ChordEmit<Keyboard> = ChordEmit::new(&ONE_OF_EVENTS, Code(Keyboard::C));
+
+    const CLASS_RULES: [ChordEmit<Keyboard>; 4] =
+        [THUMB_HOME, THUMB_NOT_HOME, NON_THUMB_RIGHT, ONE_OF];
+
+    fn parse_class(keys: &[Pressed]) -> Emit<Keyboard> {
+        let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+        chord.extend_from_slice(keys).unwrap();
+        parse_with(&chord, &CLASS_RULES)
+    }
+
+    #[test]
+    fn in_group() {
+        assert_eq!(Code(Keyboard::A), parse_class(&[L_THUMB, D]));
+        assert_eq!(Code(Keyboard::A), parse_class(&[R_THUMB, SEMICOLON]));
+        assert_eq!(Identity, parse_class(&[D, L_THUMB]));
+    }
+
+    #[test]
+    fn not() {
+        assert_eq!(Code(Keyboard::B), parse_class(&[L_THUMB, Q]));
+        assert_eq!(Code(Keyboard::B), parse_class(&[L_THUMB, R_THUMB]));
+        // home row goes to THUMB_HOME, never to THUMB_NOT_HOME
+        assert_eq!(Code(Keyboard::A), parse_class(&[L_THUMB, F]));
+    }
+
+    #[test]
+    fn not_one_of() {
+        assert_eq!(Shift(&Identity), parse_class(&[R_THUMB, P]));
+        assert_eq!(Shift(&Identity), parse_class(&[R_THUMB, M]));
+        assert_eq!(Identity, parse_class(&[R_THUMB, Q]));
+        assert_eq!(Identity, parse_class(&[R_THUMB, L_THUMB]));
+    }
+
+    #[test]
+    fn one_of() {
+        assert_eq!(Code(Keyboard::C), parse_class(&[Q, R_THUMB]));
+        assert_eq!(Code(Keyboard::C), parse_class(&[P, R_THUMB]));
+        assert_eq!(Identity, parse_class(&[W, R_THUMB]));
+    }
+
+    const TAB_ANY_EVENTS: [ChordEvent; 2] = [On(L_THUMB), Any];
+    const TAB_ANY_PREFIX: ChordEmit<Keyboard> = ChordEmit::new(&TAB_ANY_EVENTS, Code(Keyboard::A));
+    const TAB_ANY_EXACT: ChordEmit<Keyboard> =
+        ChordEmit::new(&TAB_ANY_EVENTS, Code(Keyboard::B)).mode(Match::Exact);
+
+    const UNORDERED_EVENTS: [ChordEvent; 3] = [On(L_THUMB), Both(F, J), RAny];
+    const UNORDERED: ChordEmit<Keyboard> =
+        ChordEmit::new(&UNORDERED_EVENTS, Code(Keyboard::C)).mode(Match::Unordered);
+
+    const OPTIONAL_EXACT_EVENTS: [ChordEvent; 2] = [On(R_THUMB), Optional(&On(P))];
+    const OPTIONAL_EXACT: ChordEmit<Keyboard> =
+        ChordEmit::new(&OPTIONAL_EXACT_EVENTS, Code(Keyboard::D)).mode(Match::Exact);
+
+    fn parse_one(rule: ChordEmit<Keyboard>, keys: &[Pressed]) -> Emit<Keyboard> {
+        let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+        chord.extend_from_slice(keys).unwrap();
+        parse_with(&chord, &[rule])
+    }
+
+    #[test]
+    fn prefix_match() {
+        assert_eq!(Code(Keyboard::A), parse_one(TAB_ANY_PREFIX, &[L_THUMB, Q]));
+        assert_eq!(
+            Code(Keyboard::A),
+            parse_one(TAB_ANY_PREFIX, &[L_THUMB, Q, W, E, R])
+        );
+    }
+
+    #[test]
+    fn exact_match() {
+        assert_eq!(Code(Keyboard::B), parse_one(TAB_ANY_EXACT, &[L_THUMB, Q]));
+        assert_eq!(Identity, parse_one(TAB_ANY_EXACT, &[L_THUMB, Q, W, E, R]));
+        assert_eq!(Identity, parse_one(TAB_ANY_EXACT, &[L_THUMB]));
+        assert_eq!(Identity, parse_one(TAB_ANY_EXACT, &[Q, L_THUMB]));
+    }
+
+    #[test]
+    fn exact_match_optional() {
+        assert_eq!(Code(Keyboard::D), parse_one(OPTIONAL_EXACT, &[R_THUMB]));
+        assert_eq!(Code(Keyboard::D), parse_one(OPTIONAL_EXACT, &[R_THUMB, P]));
+        assert_eq!(Identity, parse_one(OPTIONAL_EXACT, &[R_THUMB, O]));
+        // a prefix rule still needs a key for every event
+        let prefix = ChordEmit::new(&OPTIONAL_EXACT_EVENTS, Code(Keyboard::D));
+        assert_eq!(Identity, parse_one(prefix, &[R_THUMB]));
+        let prefix = ChordEmit::new(&OPTIONAL_EXACT_EVENTS, Code(Keyboard::D));
+        assert_eq!(Code(Keyboard::D), parse_one(prefix, &[R_THUMB, O]));
+    }
+
+    #[test]
+    fn unordered_match() {
+        for keys in [
+            [L_THUMB, F, J, P],
+            [J, P, F, L_THUMB],
+            [P, J, L_THUMB, F],
+            [F, L_THUMB, P, J],
+        ] {
+            assert_eq!(Code(Keyboard::C), parse_one(UNORDERED, &keys));
+        }
+        // RAny may not reuse J
+        assert_eq!(Identity, parse_one(UNORDERED, &[L_THUMB, F, J]));
+        // Extra key
+        assert_eq!(Identity, parse_one(UNORDERED, &[L_THUMB, F, J, P, O]));
+        // RAny needs a right key
+        assert_eq!(Identity, parse_one(UNORDERED, &[L_THUMB, F, J, Q]));
+    }
+
+    #[test]
+    fn unordered_long_chord() {
+        const ANY_EVENTS: [ChordEvent; 1] = [Any];
+        let mut chord = [Q; 130];
+        chord[100] = L_THUMB;
+        chord[101] = F;
+        chord[102] = J;
+        chord[103] = P;
+        assert!(!rule_match(
+            &chord[..104],
+            &UNORDERED_EVENTS,
+            Match::Unordered
+        ));
+        assert!(!rule_match(&chord[..128], &ANY_EVENTS, Match::Unordered));
+        assert!(!rule_match(&chord, &ANY_EVENTS, Match::Unordered));
+        assert!(rule_match(
+            &chord[100..104],
+            &UNORDERED_EVENTS,
+            Match::Unordered
+        ));
+    }
+
+    const TAB_SPC_EVENTS: [ChordEvent; 1] = [Both(L_THUMB, R_THUMB)];
+    const TAB_SPC: ChordEmit<Keyboard> = ChordEmit::new(&TAB_SPC_EVENTS, Code(Keyboard::Escape));
+
+    const TAB_PAIR_EVENTS: [ChordEvent; 1] = [All(&[L_THUMB, R_THUMB])];
+    const TAB_PAIR: ChordEmit<Keyboard> = ChordEmit::new(&TAB_PAIR_EVENTS, Code(Keyboard::Escape));
+
+    const TAB_LAYER_EVENTS: [ChordEvent; 2] = [On(L_THUMB), LAny];
+    const TAB_LAYER: ChordEmit<Keyboard> = ChordEmit::new(&TAB_LAYER_EVENTS, Shift(&Identity));
+
+    const TAB_Q_EVENTS: [ChordEvent; 2] = [On(L_THUMB), On(Q)];
+    const TAB_Q: ChordEmit<Keyboard> = ChordEmit::new(&TAB_Q_EVENTS, Code(Keyboard::Keyboard1));
+
+    const TAB_EXACT: ChordEmit<Keyboard> =
+        ChordEmit::new(&TAB_LAYER_EVENTS, Code(Keyboard::Tab)).mode(Match::Exact);
+
+    const THUMB_RIGHT_EVENTS: [ChordEvent; 2] = [In(&THUMBS), RAny];
+    const THUMB_RIGHT: ChordEmit<Keyboard> = ChordEmit::new(&THUMB_RIGHT_EVENTS, Code(Keyboard::E));
+
+    const TAB_ANY_HIGH: ChordEmit<Keyboard> =
+        ChordEmit::new(&TAB_ANY_EVENTS, Code(Keyboard::F1)).priority(1);
+
+    #[test]
+    fn specificity_ignores_order() {
+        let table = [TAB_ANY_PREFIX, TAB_LAYER, TAB_EXACT, TAB_Q, TAB_PAIR];
+        let rules = Rules::by_specificity(&table).unwrap();
+
+        // concrete keys first
+        assert_eq!(Code(Keyboard::Escape), rules.parse(&[L_THUMB, R_THUMB]));
+        assert_eq!(Code(Keyboard::Keyboard1), rules.parse(&[L_THUMB, Q]));
+        // then exact over prefix
+        assert_eq!(Code(Keyboard::Tab), rules.parse(&[L_THUMB, W]));
+        // then side wildcard over Any
+        assert_eq!(Shift(&Identity), rules.parse(&[L_THUMB, W, E]));
+        assert_eq!(Code(Keyboard::A), rules.parse(&[L_THUMB, P]));
+
+        // in order mode the first rule swallows everything
+        let ordered = Rules::ordered(&table);
+        assert_eq!(Code(Keyboard::A), ordered.parse(&[L_THUMB, R_THUMB]));
+    }
+
+    #[test]
+    fn specificity_ambiguous() {
+        let table = [TAB_Q, TAB_ANY_PREFIX, TAB_LAYER, TAB_ANY_PREFIX];
+        assert_eq!(
+            Some(Ambiguous::Overlap(1, 3)),
+            Rules::by_specificity(&table).err()
+        );
+
+        // both match a thumb followed by a right home row key
+        let table = [THUMB_HOME, ONE_OF, THUMB_RIGHT];
+        assert_eq!(
+            Some(Ambiguous::Overlap(0, 2)),
+            Rules::by_specificity(&table).err()
+        );
+    }
+
+    #[test]
+    fn specificity_explicit_priority() {
+        let table = [TAB_ANY_PREFIX, TAB_ANY_HIGH];
+        let rules = Rules::by_specificity(&table).unwrap();
+        assert_eq!(Code(Keyboard::F1), rules.parse(&[L_THUMB, P]));
+    }
+
+    #[test]
+    fn specificity_disjoint_rules() {
+        // same shape, different keys
+        let table = [SHIFT_L, TAB_LAYER, Q_CODE, W_STRING, OPT_CTRL_R1, ALL_MOD];
+        assert!(Rules::by_specificity(&table).is_ok());
+    }
+
+    #[test]
+    fn specificity_search_limit() {
+        // never the same chord, but too many to try
+        const HOME_Q: [ChordEvent; 5] = [
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            On(Q),
+        ];
+        const HOME_W: [ChordEvent; 5] = [
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            In(&HOME_ROW),
+            On(W),
+        ];
+        let table = [
+            ChordEmit::new(&HOME_Q, Code(Keyboard::A)),
+            ChordEmit::new(&HOME_W, Code(Keyboard::B)),
+        ];
+        assert!(matches!(
+            Rules::by_specificity(&table),
+            Err(Ambiguous::Unchecked(0, 1))
+        ));
+        let table = [
+            ChordEmit::new(&HOME_Q, Code(Keyboard::A)).priority(1),
+            ChordEmit::new(&HOME_W, Code(Keyboard::B)),
+        ];
+        assert!(Rules::by_specificity(&table).is_ok());
+    }
+
+    // Both matches when either of its keys is in either place, existing
+    // configs rely on it. All is the exact pair
+    #[test]
+    fn both_needs_one_key() {
+        let ret = Pressed(Key::Right(KeyId::K17));
+        assert_eq!(Code(Keyboard::Escape), parse_one(TAB_SPC, &[L_THUMB, ret]));
+        assert_eq!(Code(Keyboard::Escape), parse_one(TAB_SPC, &[ret, R_THUMB]));
+        assert_eq!(
+            Code(Keyboard::Escape),
+            parse_one(TAB_SPC, &[R_THUMB, L_THUMB])
+        );
+        assert_eq!(Identity, parse_one(TAB_SPC, &[ret, Q]));
+        assert_eq!(
+            Ctrl(&Shift(&Code(Keyboard::A))),
+            parse_one(Q_CODE, &[K, J, Q])
+        );
+
+        const PAIR_EVENTS: [ChordEvent; 1] = [All(&[L_THUMB, R_THUMB])];
+        let pair = ChordEmit::new(&PAIR_EVENTS, Code(Keyboard::Escape));
+        assert_eq!(Identity, parse_one(pair, &[L_THUMB, ret]));
+        let pair = ChordEmit::new(&PAIR_EVENTS, Code(Keyboard::Escape));
+        assert_eq!(Code(Keyboard::Escape), parse_one(pair, &[R_THUMB, L_THUMB]));
+    }
+
+    #[test]
+    fn context() {
+        assert_eq!(Some(1), SHIFT_L.context(&[D, H, J]));
+        assert_eq!(Some(2), CONTROL_SHIFT_R.context(&[H, J, D]));
+        assert_eq!(Some(1), TAB_Q.context(&[L_THUMB, Q]));
+        assert_eq!(None, TAB_SPC.context(&[L_THUMB, R_THUMB]));
+        assert_eq!(None, UNORDERED.context(&[L_THUMB, F, J, P]));
+    }
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const Q_ALONE: ChordEmit<Keyboard> = ChordEmit::new(&Q_EVENTS, Code(Keyboard::Q));
+    const D_EVENTS: [ChordEvent; 1] = [On(D)];
+    const D_ALONE: ChordEmit<Keyboard> = ChordEmit::new(&D_EVENTS, Code(Keyboard::D));
+
+    #[test]
+    fn settled() {
+        let table = [SHIFT_L, D_ALONE, Q_CODE, Q_ALONE, TAB_PAIR, TAB_Q];
+        let rules = Rules::ordered(&table);
+        // Q only ends Q_CODE, it never starts a longer rule
+        assert!(rules.settled(&[Q]));
+        // D may still become shift
+        assert!(!rules.settled(&[D]));
+        // nothing matches yet
+        assert!(!rules.settled(&[H]));
+        assert!(!rules.settled(&[L_THUMB]));
+        // no more keys matter for the pair
+        assert!(rules.settled(&[L_THUMB, R_THUMB]));
+        // the held keys apply to every further tap
+        assert!(!rules.settled(&[L_THUMB, Q]));
+        assert_eq!(bit(Q.0), rules.settled_keys());
+
+        // any key may start a Both with one of its keys after it
+        let table = [Q_ALONE, TAB_SPC];
+        assert!(!Rules::ordered(&table).settled(&[Q]));
+
+        let table = [Q_ALONE.mode(Match::Exact)];
+        assert!(!Rules::ordered(&table).settled(&[Q]));
+    }
 }
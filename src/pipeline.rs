@@ -0,0 +1,225 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    lex::{Event, Millis, Pressed},
+    parse::{Emit, Rules},
+    report::Overflow,
+};
+
+// One step between the switches and the host, every hook passes its input on
+// untouched unless overridden. Stages chain as tuples, (A, B) runs A first
+pub trait Stage {
+    // Events on their way to the stack, also called with none on every tick.
+    // Buffers come in the capacities of the engine, see Engine::capacities
+    fn events<const N: usize>(&mut self, _events: &mut Vec<Event, N>, _now: Millis) {}
+
+    fn chord<const N: usize>(&mut self, _chord: &mut Vec<Pressed, N>) {}
+
+    fn emit(&mut self, _chord: &[Pressed], emit: Emit<Keyb>) -> Emit<Keyb> {
+        emit
+    }
+
+    // The reports of the last emit were added to report from start on, before
+    // those of any later emit. Empty when the emit makes none
+    fn built<const R: usize>(
+        &mut self,
+        _report: &mut Vec<Keyb, R>,
+        _start: usize,
+    ) -> Result<(), Overflow> {
+        Ok(())
+    }
+
+    // Codes that do not fit the report are left out and flagged, see
+    // Engine::overflowed
+    fn report<const R: usize>(&mut self, _report: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+        Ok(())
+    }
+
+    // Every engine tick, anything added to the report is sent like a resolved
+    // chord. The rules are those of the engine
+    fn tick<const R: usize>(
+        &mut self,
+        _now: Millis,
+        _rules: &Rules<Keyb>,
+        _report: &mut Vec<Keyb, R>,
+    ) -> Result<(), Overflow> {
+        Ok(())
+    }
+
+    // The engine forgot every pending event and held key, see Engine::clear
+    fn clear(&mut self) {}
+}
+
+impl Stage for () {}
+
+impl<A: Stage, B: Stage> Stage for (A, B) {
+    fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, now: Millis) {
+        self.0.events(events, now);
+        self.1.events(events, now);
+    }
+
+    fn chord<const N: usize>(&mut self, chord: &mut Vec<Pressed, N>) {
+        self.0.chord(chord);
+        self.1.chord(chord);
+    }
+
+    fn emit(&mut self, chord: &[Pressed], emit: Emit<Keyb>) -> Emit<Keyb> {
+        let emit = self.0.emit(chord, emit);
+        self.1.emit(chord, emit)
+    }
+
+    fn built<const R: usize>(
+        &mut self,
+        report: &mut Vec<Keyb, R>,
+        start: usize,
+    ) -> Result<(), Overflow> {
+        let first = self.0.built(report, start);
+        self.1.built(report, start).and(first)
+    }
+
+    // the stage after a full report still runs
+    fn report<const R: usize>(&mut self, report: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+        let first = self.0.report(report);
+        self.1.report(report).and(first)
+    }
+
+    fn tick<const R: usize>(
+        &mut self,
+        now: Millis,
+        rules: &Rules<Keyb>,
+        report: &mut Vec<Keyb, R>,
+    ) -> Result<(), Overflow> {
+        let first = self.0.tick(now, rules, report);
+        self.1.tick(now, rules, report).and(first)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+        self.1.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::Engine,
+        lex::{qwerty::*, Key},
+        parse::{
+            ChordEmit,
+            ChordEvent::{self, *},
+            Emit::*,
+        },
+    };
+    use Event::*;
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const W_EVENTS: [ChordEvent; 1] = [On(W)];
+    const E_EVENTS: [ChordEvent; 1] = [On(E)];
+    const RULES: [ChordEmit<Keyb>; 3] = [
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&W_EVENTS, Code(Keyb::W)),
+        ChordEmit::new(&E_EVENTS, Code(Keyb::E)),
+    ];
+
+    // Replaces one code with another, like autocorrect would
+    struct Remap(Keyb, Keyb);
+
+    impl Stage for Remap {
+        fn emit(&mut self, _chord: &[Pressed], emit: Emit<Keyb>) -> Emit<Keyb> {
+            match emit {
+                Code(code) if code == self.0 => Code(self.1),
+                _ => emit,
+            }
+        }
+    }
+
+    // Ignores a broken switch
+    struct Mute(Key);
+
+    impl Stage for Mute {
+        fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, _now: Millis) {
+            events.retain(|event| Key::from(*event) != self.0);
+        }
+    }
+
+    #[derive(Default)]
+    struct Log {
+        chords: usize,
+        reports: usize,
+    }
+
+    impl Stage for Log {
+        fn chord<const N: usize>(&mut self, _chord: &mut Vec<Pressed, N>) {
+            self.chords += 1;
+        }
+
+        fn report<const R: usize>(&mut self, _report: &mut Vec<Keyb, R>) -> Result<(), Overflow> {
+            self.reports += 1;
+            Ok(())
+        }
+    }
+
+    // Taps a key once the clock passes a time
+    struct Timer(Millis, Key);
+
+    impl Stage for Timer {
+        fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, now: Millis) {
+            if now >= self.0 && self.0 > 0 {
+                events.push(Down(self.1)).unwrap();
+                events.push(Up(self.1)).unwrap();
+                self.0 = 0;
+            }
+        }
+    }
+
+    fn type_qwe<P: Stage>(engine: &mut Engine<16, 8, P>) -> Vec<Keyb, 8> {
+        for key in [Q, W, E] {
+            engine.feed(Down(key.into()));
+            engine.feed(Up(key.into()));
+        }
+        engine.drain_reports()
+    }
+
+    #[test]
+    fn stages_in_order() {
+        let mut engine = Engine::<16, 8>::new(Rules::ordered(&RULES))
+            .pipeline((Remap(Keyb::Q, Keyb::W), Remap(Keyb::W, Keyb::E)));
+        assert_eq!(
+            &[Keyb::E, Keyb::Out, Keyb::E, Keyb::Out, Keyb::E],
+            type_qwe(&mut engine).as_slice()
+        );
+
+        let mut engine = Engine::<16, 8>::new(Rules::ordered(&RULES))
+            .pipeline((Remap(Keyb::W, Keyb::E), Remap(Keyb::Q, Keyb::W)));
+        assert_eq!(
+            &[Keyb::W, Keyb::Out, Keyb::E, Keyb::Out, Keyb::E],
+            type_qwe(&mut engine).as_slice()
+        );
+    }
+
+    #[test]
+    fn event_stage() {
+        let mut engine =
+            Engine::<16, 8>::new(Rules::ordered(&RULES)).pipeline((Mute(W.into()), Log::default()));
+        assert_eq!(
+            &[Keyb::Q, Keyb::Out, Keyb::E],
+            type_qwe(&mut engine).as_slice()
+        );
+        assert_eq!(2, engine.stages().1.chords);
+        assert_eq!(2, engine.stages().1.reports);
+    }
+
+    #[test]
+    fn events_on_tick() {
+        let mut engine =
+            Engine::<16, 8>::new(Rules::ordered(&RULES)).pipeline(Timer(100, Q.into()));
+        engine.tick(50);
+        assert!(engine.drain_reports().is_empty());
+        engine.tick(100);
+        assert_eq!(&[Keyb::Q], engine.drain_reports().as_slice());
+        engine.tick(200);
+        assert!(engine.drain_reports().is_empty());
+    }
+}
@@ -2,86 +2,387 @@ use heapless::Vec;
 use usbd_human_interface_device::page::Keyboard as Keyb;
 
 use crate::{
-    lex::{chord, Event, Key, Pressed, PRESS_SIZE, REPORT_SIZE, STACK_SIZE},
-    parse::{parse_with, ChordEmit, Emit},
+    lex::{
+        bit, chord, chord_grouped, stream, Event, Key, KeyId, Pressed, Stream, PRESS_SIZE,
+        REPORT_SIZE,
+    },
+    parse::{ChordEmit, Emit, Override, Rules},
+    pipeline::Stage,
 };
 
-pub fn eval<const RULE_SIZE: usize>(
-    stack: &mut Vec<Event, STACK_SIZE>,
+pub fn eval<const RULE_SIZE: usize, const S: usize>(
+    stack: &mut Vec<Event, S>,
     rules: &[ChordEmit<Keyb>; RULE_SIZE],
 ) -> Vec<Keyb, REPORT_SIZE> {
-    let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
+    eval_by(stack, &Rules::ordered(rules))
+}
+
+pub fn eval_by<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    rules: &Rules<Keyb>,
+) -> Vec<Keyb, REPORT_SIZE> {
+    eval_with(stack, rules, Fallback::default())
+}
 
+pub fn eval_with<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    rules: &Rules<Keyb>,
+    fallback: Fallback,
+) -> Vec<Keyb, REPORT_SIZE> {
     let chrd = chord(stack);
+    eval_chord_with(&chrd, rules, fallback)
+}
+
+// What a chord of several keys emits when no rule matches it, or the rule
+// found leaves some of its keys unused
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Fallback {
+    // Every key as a tap of its own, in press order
+    ReplayAll,
+    // What the rule found stands for, the keys it leaves unused are dropped.
+    // The last key when no rule matches, NB: the keys before it are lost
+    #[default]
+    Matched,
+    // Only the last key, whatever rule the keys before it match
+    LastOnly,
+    // What the rule found stands for, nothing at all when no rule matches
+    Drop,
+}
+
+// Like eval_by, but each group of keys makes chords of its own, see lex::chord_grouped
+pub fn eval_grouped<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    rules: &Rules<Keyb>,
+    group: impl Fn(Key) -> usize,
+) -> Vec<Keyb, REPORT_SIZE> {
+    let chrd = chord_grouped(stack, group);
+    eval_chord(&chrd, rules)
+}
+
+// Like eval_by, but a held root emits together with each inner key right away
+pub fn eval_stream<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    rules: &Rules<Keyb>,
+    state: &mut Stream,
+) -> Vec<Keyb, REPORT_SIZE> {
+    let chrd = stream(stack, state);
+    eval_chord(&chrd, rules)
+}
+
+// Roots that resolve the same however the chord goes on, see Rules::settled
+#[derive(Debug, Default)]
+pub struct Early {
+    settled: u128,
+    // root emitted on press, its chord is swallowed once it resolves
+    pub(crate) emitted: Option<Key>,
+}
+
+impl Early {
+    pub fn new(rules: &Rules<Keyb>) -> Self {
+        Early {
+            settled: rules.settled_keys(),
+            emitted: None,
+        }
+    }
+
+    pub(crate) fn is_settled(&self, key: Key) -> bool {
+        self.settled & bit(key) != 0
+    }
+}
+
+// Like eval_by, but a root that no further key can change is emitted on press
+pub fn eval_early<const S: usize>(
+    stack: &mut Vec<Event, S>,
+    rules: &Rules<Keyb>,
+    state: &mut Early,
+) -> Vec<Keyb, REPORT_SIZE> {
+    let chrd = chord(stack);
+    let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
+    match chrd.first() {
+        Some(Pressed(root)) if state.emitted == Some(*root) => state.emitted = None,
+        _ => {
+            eval_chord_staged::<PRESS_SIZE, _>(
+                &chrd,
+                rules,
+                Fallback::default(),
+                &mut (),
+                &mut keyboard,
+            )
+            .ok();
+        }
+    }
+    if let [Event::Down(root)] = stack.as_slice() {
+        if state.emitted.is_none() && state.is_settled(*root) {
+            state.emitted = Some(*root);
+            let root = [Pressed(*root)];
+            eval_chord_staged::<PRESS_SIZE, _>(
+                &root,
+                rules,
+                Fallback::default(),
+                &mut (),
+                &mut keyboard,
+            )
+            .ok();
+        }
+    }
+    override_reports(rules.overrides, &mut keyboard).ok();
+    keyboard
+}
 
+pub fn eval_chord(chrd: &[Pressed], rules: &Rules<Keyb>) -> Vec<Keyb, REPORT_SIZE> {
+    eval_chord_with(chrd, rules, Fallback::default())
+}
+
+// Reports past REPORT_SIZE are dropped, see eval_chord_staged
+pub fn eval_chord_with(
+    chrd: &[Pressed],
+    rules: &Rules<Keyb>,
+    fallback: Fallback,
+) -> Vec<Keyb, REPORT_SIZE> {
+    let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
+    eval_chord_staged::<PRESS_SIZE, _>(chrd, rules, fallback, &mut (), &mut keyboard).ok();
+    override_reports(rules.overrides, &mut keyboard).ok();
+    keyboard
+}
+
+// A buffer is full, the reports that did not fit are dropped whole
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Overflow;
+
+// Like eval_chord_with, every emit passes the stage before it is reported.
+// Reports go after those already in keyboard, as many as fit. Overrides are
+// left to the caller, once every stage had its say, see override_reports.
+// Held keys and a tap make chords of N keys at most
+pub fn eval_chord_staged<const N: usize, const R: usize>(
+    chrd: &[Pressed],
+    rules: &Rules<Keyb>,
+    fallback: Fallback,
+    stage: &mut impl Stage,
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<(), Overflow> {
     if chrd.is_empty() {
-        return keyboard;
+        return Ok(());
     }
 
-    let emit = parse_with(&chrd, rules);
+    let mut chrd = chrd;
+    let rule = rules.find(chrd);
+    let held = rule.and_then(|rule| rule.context(chrd));
+    // keys held as context are used by every tap after them
+    let consumed = match (rule, held) {
+        (None, _) => 0,
+        (Some(_), Some(held)) if held > 0 => chrd.len(),
+        (Some(rule), _) => rule.consumed(chrd),
+    };
+    if consumed < chrd.len() && chrd.len() > 1 {
+        match fallback {
+            Fallback::ReplayAll => {
+                for tap in chrd {
+                    let identity = stage.emit(&[*tap], rules.parse(&[*tap]));
+                    add(keyboard, stage, identity, identity, &tap.0)?;
+                }
+                return Ok(());
+            }
+            Fallback::Drop if consumed == 0 => return Ok(()),
+            Fallback::Matched | Fallback::Drop if consumed > 0 => chrd = &chrd[..consumed],
+            // LastOnly, or no rule matched
+            _ => {
+                let last = chrd.last().unwrap();
+                let identity = stage.emit(&[*last], rules.parse(&[*last]));
+                return add(keyboard, stage, identity, identity, &last.0);
+            }
+        }
+    }
+    let Pressed(first) = chrd.first().unwrap();
+    if let Some(held) = held.filter(|held| *held > 0) {
+        // held keys apply to every tapped key, one report each
+        for tap in &chrd[held..] {
+            let mut tap_chord: Vec<Pressed, N> = Vec::new();
+            tap_chord
+                .extend_from_slice(&chrd[..held])
+                .and_then(|()| tap_chord.push(*tap).map_err(|_| ()))
+                .map_err(|_| Overflow)?;
+            let emit = stage.emit(&tap_chord, rules.parse(&tap_chord));
+            let identity = rules.parse(&[*tap]);
+            add(keyboard, stage, emit, identity, first)?;
+        }
+        return Ok(());
+    }
+
+    let emit = stage.emit(chrd, rules.parse(chrd));
 
     let identity = if chrd.len() > 1 {
-        let mut identity_chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
-        let last = chrd.last().unwrap();
-        identity_chord.push(*last).unwrap();
-        parse_with(&identity_chord, rules)
+        rules.parse(&chrd[chrd.len() - 1..])
     } else {
         emit
     };
-    let Pressed(first) = chrd.first().unwrap();
-    build_keyboard_report(emit, identity, first, &mut keyboard);
-    keyboard
+    add(keyboard, stage, emit, identity, first)
+}
+
+// Adds the report of one emit, the stage sees it once built, see Stage::built
+fn add<const R: usize>(
+    keyboard: &mut Vec<Keyb, R>,
+    stage: &mut impl Stage,
+    emit: Emit<Keyb>,
+    identity: Emit<Keyb>,
+    first: &Key,
+) -> Result<(), Overflow> {
+    let start = keyboard.len();
+    append(keyboard, |keyboard| {
+        build_keyboard_report(emit, identity, first, keyboard)
+    })?;
+    // past the separator append put in
+    let start = start + usize::from(keyboard.get(start) == Some(&Keyb::Out));
+    stage.built(keyboard, start)
+}
+
+// Adds one report after those in keyboard, or nothing at all if it does not fit
+pub(crate) fn append<const R: usize>(
+    keyboard: &mut Vec<Keyb, R>,
+    build: impl FnOnce(&mut Vec<Keyb, R>) -> Result<(), Overflow>,
+) -> Result<(), Overflow> {
+    let start = keyboard.len();
+    let built = if !keyboard.is_empty() && keyboard.last() != Some(&Keyb::Out) {
+        push(keyboard, Keyb::Out).and_then(|()| build(keyboard))
+    } else {
+        build(keyboard)
+    };
+    if built.is_err() {
+        keyboard.truncate(start);
+    }
+    built
 }
 
-fn build_keyboard_report(
+fn push<const R: usize>(keyboard: &mut Vec<Keyb, R>, code: Keyb) -> Result<(), Overflow> {
+    keyboard.push(code).map_err(|_| Overflow)
+}
+
+pub(crate) fn build_keyboard_report<const R: usize>(
     emit: Emit<Keyb>,
     identity: Emit<Keyb>,
     first: &Key,
-    keyboard: &mut Vec<Keyb, REPORT_SIZE>,
-) {
-    let emit = build_keyboard_report_modifiers(emit, first, keyboard);
-    build_keyboard_report_identity(emit, identity, keyboard);
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<(), Overflow> {
+    let emit = build_keyboard_report_modifiers(emit, first, keyboard)?;
+    build_keyboard_report_identity(emit, identity, keyboard)
 }
 
-fn build_keyboard_report_modifiers(
+// Each report of a key is overridden based on the modifiers it goes out with,
+// whether the rule, a stage or a behavior put them there. Reports that no
+// longer fit are dropped
+pub(crate) fn override_reports<const R: usize>(
+    overrides: &[Override<Keyb>],
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<(), Overflow> {
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let mut overridden: Vec<Keyb, R> = Vec::new();
+    let mut result = Ok(());
+    for codes in keyboard.split(|code| *code == Keyb::Out) {
+        if codes.is_empty() {
+            continue;
+        }
+        result = append(&mut overridden, |overridden| {
+            override_report(overrides, codes, overridden)
+        });
+        if result.is_err() {
+            break;
+        }
+    }
+    *keyboard = overridden;
+    result
+}
+
+fn override_report<const R: usize>(
+    overrides: &[Override<Keyb>],
+    codes: &[Keyb],
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<(), Overflow> {
+    let found = overrides.iter().find(|over| {
+        codes
+            .iter()
+            .filter(|code| !is_modifier(**code))
+            .eq([&over.key])
+            && over
+                .mods
+                .iter()
+                .all(|m| codes.iter().any(|code| sideless(*code) == sideless(*m)))
+    });
+    let Some(over) = found else {
+        return keyboard.extend_from_slice(codes).map_err(|_| Overflow);
+    };
+    for code in codes {
+        let used = !over.keep && over.mods.iter().any(|m| sideless(*m) == sideless(*code));
+        if is_modifier(*code) && !used {
+            push(keyboard, *code)?;
+        }
+    }
+    // modifiers of the override go on the side of those in the report
+    let side = if codes.iter().any(|code| sideless(*code) != *code) {
+        Key::Right(KeyId::K1)
+    } else {
+        Key::Left(KeyId::K1)
+    };
+    let emit = build_keyboard_report_modifiers(over.emit, &side, keyboard)?;
+    build_keyboard_report_identity(emit, Emit::Code(over.key), keyboard)
+}
+
+pub(crate) fn is_modifier(code: Keyb) -> bool {
+    matches!(
+        sideless(code),
+        Keyb::LeftControl | Keyb::LeftShift | Keyb::LeftAlt | Keyb::LeftGUI
+    )
+}
+
+pub(crate) fn sideless(code: Keyb) -> Keyb {
+    match code {
+        Keyb::RightGUI => Keyb::LeftGUI,
+        Keyb::RightAlt => Keyb::LeftAlt,
+        Keyb::RightShift => Keyb::LeftShift,
+        Keyb::RightControl => Keyb::LeftControl,
+        _ => code,
+    }
+}
+
+fn build_keyboard_report_modifiers<const R: usize>(
     emit: Emit<Keyb>,
     first: &Key,
-    keyboard: &mut Vec<Keyb, REPORT_SIZE>,
-) -> Emit<Keyb> {
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<Emit<Keyb>, Overflow> {
     match emit {
         Emit::Mod(next) => {
             if let Key::Left(_) = first {
-                keyboard.push(Keyb::LeftGUI).unwrap();
+                push(keyboard, Keyb::LeftGUI)?;
             } else {
-                keyboard.push(Keyb::RightGUI).unwrap();
+                push(keyboard, Keyb::RightGUI)?;
             }
             build_keyboard_report_modifiers(*next, first, keyboard)
         }
         Emit::Alt(next) => {
             if let Key::Left(_) = first {
-                keyboard.push(Keyb::LeftAlt).unwrap();
+                push(keyboard, Keyb::LeftAlt)?;
             } else {
-                keyboard.push(Keyb::RightAlt).unwrap();
+                push(keyboard, Keyb::RightAlt)?;
             }
             build_keyboard_report_modifiers(*next, first, keyboard)
         }
         Emit::Shift(next) => {
             if let Key::Left(_) = first {
-                keyboard.push(Keyb::LeftShift).unwrap();
+                push(keyboard, Keyb::LeftShift)?;
             } else {
-                keyboard.push(Keyb::RightShift).unwrap();
+                push(keyboard, Keyb::RightShift)?;
             }
             build_keyboard_report_modifiers(*next, first, keyboard)
         }
         Emit::Ctrl(next) => {
             if let Key::Left(_) = first {
-                keyboard.push(Keyb::LeftControl).unwrap();
+                push(keyboard, Keyb::LeftControl)?;
             } else {
-                keyboard.push(Keyb::RightControl).unwrap();
+                push(keyboard, Keyb::RightControl)?;
             }
             build_keyboard_report_modifiers(*next, first, keyboard)
         }
-        _ => emit,
+        _ => Ok(emit),
     }
 }
 
@@ -100,37 +401,39 @@ fn report_from_chr(chr: char) -> Keyb {
     }
 }
 
-fn build_keyboard_report_identity(
+fn build_keyboard_report_identity<const R: usize>(
     emit: Emit<Keyb>,
     identity: Emit<Keyb>,
-    keyboard: &mut Vec<Keyb, REPORT_SIZE>,
-) {
+    keyboard: &mut Vec<Keyb, R>,
+) -> Result<(), Overflow> {
     match emit {
         Emit::String(str) => {
             for chr in str.chars() {
-                keyboard.push(report_from_chr(chr)).unwrap();
-                keyboard.push(Keyb::Out).unwrap();
+                push(keyboard, report_from_chr(chr))?;
+                push(keyboard, Keyb::Out)?;
             }
+            Ok(())
         }
-        Emit::Code(code) => {
-            keyboard.push(code).unwrap();
+        Emit::Code(code) => push(keyboard, code),
+        // an identity of its own would never end
+        Emit::Identity if identity != Emit::Identity => {
+            build_keyboard_report_identity(identity, identity, keyboard)
         }
-        Emit::Identity => {
-            build_keyboard_report_identity(identity, identity, keyboard);
-        }
-        _ => {}
+        _ => Ok(()),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::alias;
     use crate::lex::KeyId;
     use crate::parse::Emit::*;
     use crate::{
-        lex::{Key, REPORT_SIZE},
-        parse::Emit,
+        lex::{qwerty::*, Event, Key, Pressed, PRESS_SIZE, REPORT_SIZE, STACK_SIZE},
+        parse::{ChordEmit, ChordEvent, ChordEvent::On, Emit, Match, Override, Rules},
         report::{
             build_keyboard_report, build_keyboard_report_identity, build_keyboard_report_modifiers,
+            eval_chord_staged, eval_with, override_reports, Fallback, Overflow,
         },
     };
     use heapless::Vec;
@@ -141,7 +444,7 @@ mod tests {
         let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
         let emit = crate::parse::Emit::Identity;
         let identity = Emit::Code(Keyb::Q);
-        build_keyboard_report_identity(emit, identity, &mut keyboard);
+        build_keyboard_report_identity(emit, identity, &mut keyboard).unwrap();
         assert_eq!(Keyb::Q, keyboard[0]);
     }
 
@@ -150,7 +453,7 @@ mod tests {
         let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
         let emit = Emit::String("Hello");
         let identity = crate::parse::Emit::Identity;
-        build_keyboard_report_identity(emit, identity, &mut keyboard);
+        build_keyboard_report_identity(emit, identity, &mut keyboard).unwrap();
         assert_eq!(Keyb::H, keyboard[0]);
         assert_eq!(Keyb::Out, keyboard[1]);
         assert_eq!(Keyb::E, keyboard[2]);
@@ -167,7 +470,7 @@ mod tests {
         let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
         let emit = Emit::Identity;
         let identity = Emit::Code(Keyb::A);
-        build_keyboard_report_identity(emit, identity, &mut keyboard);
+        build_keyboard_report_identity(emit, identity, &mut keyboard).unwrap();
         assert_eq!(Keyb::A, keyboard[0]);
     }
 
@@ -176,7 +479,7 @@ mod tests {
         let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
         let emit = Emit::Shift(&Emit::Identity);
         let first = &crate::lex::Key::Left(KeyId::K8);
-        let emit = build_keyboard_report_modifiers(emit, first, &mut keyboard);
+        let emit = build_keyboard_report_modifiers(emit, first, &mut keyboard).unwrap();
         assert_eq!(Keyb::LeftShift, keyboard[0]);
         assert_eq!(Emit::Identity, emit);
     }
@@ -186,7 +489,7 @@ mod tests {
         let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
         let emit = Emit::Ctrl(&Emit::Identity);
         let first = &crate::lex::Key::Right(KeyId::K8);
-        let emit = build_keyboard_report_modifiers(emit, first, &mut keyboard);
+        let emit = build_keyboard_report_modifiers(emit, first, &mut keyboard).unwrap();
         assert_eq!(Keyb::RightControl, keyboard[0]);
         assert_eq!(Emit::Identity, emit);
     }
@@ -197,7 +500,7 @@ mod tests {
         let emit = Emit::Shift(&Emit::Identity);
         let first = &Key::Right(KeyId::K6); // right gui
         let identity = Emit::Code(Keyb::Keyboard1);
-        build_keyboard_report(emit, identity, first, &mut keyboard);
+        build_keyboard_report(emit, identity, first, &mut keyboard).unwrap();
         assert_eq!(Keyb::RightShift, keyboard[0]);
         assert_eq!(Keyb::Keyboard1, keyboard[1]);
     }
@@ -208,11 +511,204 @@ mod tests {
         let emit = Mod(&Ctrl(&Alt(&Shift(&Emit::Identity))));
         let first = &Key::Right(KeyId::K6); // right gui
         let identity = Emit::Code(Keyb::Q);
-        build_keyboard_report(emit, identity, first, &mut keyboard);
+        build_keyboard_report(emit, identity, first, &mut keyboard).unwrap();
         assert_eq!(Keyb::RightGUI, keyboard[0]);
         assert_eq!(Keyb::RightControl, keyboard[1]);
         assert_eq!(Keyb::RightAlt, keyboard[2]);
         assert_eq!(Keyb::RightShift, keyboard[3]);
         assert_eq!(Keyb::Q, keyboard[4]);
     }
+
+    alias!(SPC, Right, K16);
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const W_EVENTS: [ChordEvent; 1] = [On(W)];
+    const SPC_EVENTS: [ChordEvent; 1] = [On(SPC)];
+    const SPC_Q_EVENTS: [ChordEvent; 2] = [On(SPC), On(Q)];
+    // single keys only match alone, so overlapping keys match no rule
+    const RULES: [ChordEmit<Keyb>; 4] = [
+        ChordEmit::new(&SPC_Q_EVENTS, Code(Keyb::Keyboard1)),
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)).mode(Match::Exact),
+        ChordEmit::new(&W_EVENTS, Code(Keyb::W)).mode(Match::Exact),
+        ChordEmit::new(&SPC_EVENTS, Code(Keyb::Space)).mode(Match::Exact),
+    ];
+
+    fn run(fallback: Fallback, events: &[Event]) -> std::vec::Vec<Keyb> {
+        run_by(&RULES, fallback, events)
+    }
+
+    fn run_by(
+        rules: &[ChordEmit<Keyb>],
+        fallback: Fallback,
+        events: &[Event],
+    ) -> std::vec::Vec<Keyb> {
+        let rules = Rules::ordered(rules);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut out = std::vec::Vec::new();
+        for event in events {
+            stack.push(*event).unwrap();
+            out.extend(eval_with(&mut stack, &rules, fallback));
+        }
+        out
+    }
+
+    #[test]
+    fn fallback_overlap() {
+        use Event::*;
+        // W is pressed and released while Q is still down
+        let events = [Down(Q.into()), Down(W.into()), Up(W.into()), Up(Q.into())];
+        assert_eq!(
+            [Keyb::Q, Keyb::Out, Keyb::W],
+            run(Fallback::ReplayAll, &events).as_slice()
+        );
+        assert_eq!([Keyb::W], run(Fallback::Matched, &events).as_slice());
+        assert_eq!([Keyb::W], run(Fallback::LastOnly, &events).as_slice());
+        assert!(run(Fallback::Drop, &events).is_empty());
+    }
+
+    const PREFIX_RULES: [ChordEmit<Keyb>; 2] = [
+        ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+        ChordEmit::new(&W_EVENTS, Code(Keyb::W)),
+    ];
+
+    #[test]
+    fn fallback_prefix() {
+        use Event::*;
+        // the rule for Q leaves W unused
+        let events = [Down(Q.into()), Down(W.into()), Up(W.into()), Up(Q.into())];
+        let run = |fallback| run_by(&PREFIX_RULES, fallback, &events);
+        assert_eq!(
+            [Keyb::Q, Keyb::Out, Keyb::W],
+            run(Fallback::ReplayAll).as_slice()
+        );
+        assert_eq!([Keyb::Q], run(Fallback::Matched).as_slice());
+        assert_eq!([Keyb::W], run(Fallback::LastOnly).as_slice());
+        assert_eq!([Keyb::Q], run(Fallback::Drop).as_slice());
+    }
+
+    #[test]
+    fn fallback_weird_timing() {
+        use Event::*;
+        // Q is released before W, two single strokes whatever the policy
+        let events = [Down(Q.into()), Down(W.into()), Up(Q.into()), Up(W.into())];
+        for fallback in [
+            Fallback::ReplayAll,
+            Fallback::Matched,
+            Fallback::LastOnly,
+            Fallback::Drop,
+        ] {
+            assert_eq!([Keyb::Q, Keyb::W], run(fallback, &events).as_slice());
+        }
+    }
+
+    #[test]
+    fn fallback_matched_chord() {
+        use Event::*;
+        let events = [
+            Down(SPC.into()),
+            Down(Q.into()),
+            Up(Q.into()),
+            Up(SPC.into()),
+        ];
+        for fallback in [
+            Fallback::ReplayAll,
+            Fallback::Matched,
+            Fallback::LastOnly,
+            Fallback::Drop,
+        ] {
+            assert_eq!([Keyb::Keyboard1], run(fallback, &events).as_slice());
+        }
+        // three keys rolled over each other
+        let events = [
+            Down(W.into()),
+            Down(SPC.into()),
+            Down(Q.into()),
+            Up(Q.into()),
+            Up(SPC.into()),
+            Up(W.into()),
+        ];
+        assert_eq!(
+            [Keyb::W, Keyb::Out, Keyb::Space, Keyb::Out, Keyb::Q],
+            run(Fallback::ReplayAll, &events).as_slice()
+        );
+    }
+
+    const OVERRIDES: [Override<Keyb>; 2] = [
+        Override::new(
+            &[Keyb::LeftShift],
+            Keyb::DeleteBackspace,
+            Code(Keyb::DeleteForward),
+        ),
+        Override::new(&[Keyb::LeftControl], Keyb::H, Code(Keyb::LeftArrow)).keep(),
+    ];
+
+    fn overridden(emit: Emit<Keyb>, identity: Emit<Keyb>, first: Key) -> Vec<Keyb, REPORT_SIZE> {
+        let mut keyboard: Vec<Keyb, REPORT_SIZE> = Vec::new();
+        build_keyboard_report(emit, identity, &first, &mut keyboard).unwrap();
+        override_reports(&OVERRIDES, &mut keyboard).unwrap();
+        keyboard
+    }
+
+    #[test]
+    fn overrides() {
+        let right = Key::Right(KeyId::K8);
+        let left = Key::Left(KeyId::K8);
+        // a shift of either side is used up
+        assert_eq!(
+            [Keyb::DeleteForward],
+            overridden(Shift(&Identity), Code(Keyb::DeleteBackspace), right).as_slice()
+        );
+        // other modifiers stay
+        assert_eq!(
+            [Keyb::LeftControl, Keyb::DeleteForward],
+            overridden(Ctrl(&Shift(&Code(Keyb::DeleteBackspace))), Identity, left).as_slice()
+        );
+        assert_eq!(
+            [Keyb::LeftControl, Keyb::LeftArrow],
+            overridden(Ctrl(&Identity), Code(Keyb::H), left).as_slice()
+        );
+        // without the modifiers nothing changes
+        assert_eq!(
+            [Keyb::DeleteBackspace],
+            overridden(Code(Keyb::DeleteBackspace), Identity, left).as_slice()
+        );
+        assert_eq!(
+            [Keyb::LeftAlt, Keyb::H],
+            overridden(Alt(&Identity), Code(Keyb::H), left).as_slice()
+        );
+    }
+
+    #[test]
+    fn tap_run_overflow() {
+        let rules = Rules::ordered(&RULES);
+        let mut chord: Vec<Pressed, PRESS_SIZE> = Vec::new();
+        chord.push(SPC).unwrap();
+        for _ in 0..20 {
+            chord.push(Q).unwrap();
+        }
+        let mut keyboard: Vec<Keyb, 8> = Vec::new();
+        assert_eq!(
+            Err(Overflow),
+            eval_chord_staged::<PRESS_SIZE, _>(
+                &chord,
+                &rules,
+                Fallback::default(),
+                &mut (),
+                &mut keyboard
+            )
+        );
+        // whole reports only
+        assert_eq!(
+            [
+                Keyb::Keyboard1,
+                Keyb::Out,
+                Keyb::Keyboard1,
+                Keyb::Out,
+                Keyb::Keyboard1,
+                Keyb::Out,
+                Keyb::Keyboard1
+            ],
+            keyboard.as_slice()
+        );
+    }
 }
@@ -0,0 +1,499 @@
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use crate::lex::Key;
+
+// All scanners expect pulled up inputs, a pressed switch reads low
+pub trait Scan<const N: usize> {
+    type Error;
+    fn scan(&mut self) -> Result<[bool; N], Self::Error>;
+    fn keys(&self) -> [Key; N];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Diode {
+    // Rows are strobed low, columns are read
+    Col2Row,
+    // Columns are strobed low, rows are read
+    Row2Col,
+}
+
+// (row, col, key)
+pub type Position = (usize, usize, Key);
+
+// Four switches closing a rectangle in a matrix without diodes, any one of them might be a ghost
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ghost {
+    pub rows: (usize, usize),
+    pub cols: (usize, usize),
+}
+
+pub struct Matrix<I, O, const OUTS: usize, const INS: usize, const N: usize> {
+    outputs: [O; OUTS],
+    inputs: [I; INS],
+    diode: Diode,
+    positions: [Position; N],
+    clean: [[bool; INS]; OUTS],
+}
+
+impl<I, O, const OUTS: usize, const INS: usize, const N: usize> Matrix<I, O, OUTS, INS, N>
+where
+    I: InputPin,
+    O: OutputPin<Error = I::Error>,
+{
+    pub fn col2row(rows: [O; OUTS], cols: [I; INS], positions: [Position; N]) -> Self {
+        Matrix::new(rows, cols, Diode::Col2Row, positions)
+    }
+
+    pub fn row2col(rows: [I; INS], cols: [O; OUTS], positions: [Position; N]) -> Self {
+        Matrix::new(cols, rows, Diode::Row2Col, positions)
+    }
+
+    fn new(outputs: [O; OUTS], inputs: [I; INS], diode: Diode, positions: [Position; N]) -> Self {
+        for (row, col, _) in positions {
+            let (out, inp) = match diode {
+                Diode::Col2Row => (row, col),
+                Diode::Row2Col => (col, row),
+            };
+            assert!(out < OUTS && inp < INS, "Position outside of matrix");
+        }
+        Matrix {
+            outputs,
+            inputs,
+            diode,
+            positions,
+            clean: [[false; INS]; OUTS],
+        }
+    }
+
+    // Raw switch state indexed by [row][col] for Col2Row and [col][row] for Row2Col
+    pub fn scan_grid(&mut self) -> Result<[[bool; INS]; OUTS], I::Error> {
+        let mut grid = [[false; INS]; OUTS];
+        for output in self.outputs.iter_mut() {
+            output.set_high()?;
+        }
+        for (out, output) in self.outputs.iter_mut().enumerate() {
+            output.set_low()?;
+            for (inp, input) in self.inputs.iter_mut().enumerate() {
+                grid[out][inp] = input.is_low()?;
+            }
+            output.set_high()?;
+        }
+        Ok(grid)
+    }
+
+    // Like scan, but switches forming a rectangle keep their last clean state
+    pub fn scan_deghosted<F: FnMut(Ghost)>(
+        &mut self,
+        on_ghost: &mut F,
+    ) -> Result<[bool; N], I::Error> {
+        let mut grid = self.scan_grid()?;
+        let mut suspect = [[false; INS]; OUTS];
+        for o1 in 0..OUTS {
+            for o2 in o1 + 1..OUTS {
+                let both = |i: &usize| grid[o1][*i] && grid[o2][*i];
+                for i1 in (0..INS).filter(both) {
+                    for i2 in (i1 + 1..INS).filter(both) {
+                        for (o, i) in [(o1, i1), (o1, i2), (o2, i1), (o2, i2)] {
+                            suspect[o][i] = true;
+                        }
+                        on_ghost(match self.diode {
+                            Diode::Col2Row => Ghost {
+                                rows: (o1, o2),
+                                cols: (i1, i2),
+                            },
+                            Diode::Row2Col => Ghost {
+                                rows: (i1, i2),
+                                cols: (o1, o2),
+                            },
+                        });
+                    }
+                }
+            }
+        }
+        for out in 0..OUTS {
+            for inp in 0..INS {
+                if suspect[out][inp] {
+                    grid[out][inp] = self.clean[out][inp];
+                }
+            }
+        }
+        self.clean = grid;
+        Ok(self.samples(&grid))
+    }
+
+    fn samples(&self, grid: &[[bool; INS]; OUTS]) -> [bool; N] {
+        let mut samples = [false; N];
+        for (ix, (row, col, _)) in self.positions.iter().enumerate() {
+            samples[ix] = match self.diode {
+                Diode::Col2Row => grid[*row][*col],
+                Diode::Row2Col => grid[*col][*row],
+            };
+        }
+        samples
+    }
+}
+
+impl<I, O, const OUTS: usize, const INS: usize, const N: usize> Scan<N>
+    for Matrix<I, O, OUTS, INS, N>
+where
+    I: InputPin,
+    O: OutputPin<Error = I::Error>,
+{
+    type Error = I::Error;
+
+    fn scan(&mut self) -> Result<[bool; N], Self::Error> {
+        let grid = self.scan_grid()?;
+        Ok(self.samples(&grid))
+    }
+
+    fn keys(&self) -> [Key; N] {
+        self.positions.map(|(_, _, key)| key)
+    }
+}
+
+pub struct DirectPins<I, const N: usize> {
+    pins: [I; N],
+    keys: [Key; N],
+}
+
+impl<I: InputPin, const N: usize> DirectPins<I, N> {
+    pub fn new(pins: [I; N], keys: [Key; N]) -> Self {
+        DirectPins { pins, keys }
+    }
+}
+
+impl<I: InputPin, const N: usize> Scan<N> for DirectPins<I, N> {
+    type Error = I::Error;
+
+    fn scan(&mut self) -> Result<[bool; N], Self::Error> {
+        let mut samples = [false; N];
+        for (ix, pin) in self.pins.iter_mut().enumerate() {
+            samples[ix] = pin.is_low()?;
+        }
+        Ok(samples)
+    }
+
+    fn keys(&self) -> [Key; N] {
+        self.keys
+    }
+}
+
+// Chain of 74HC165, the first bit shifted out belongs to key N - 1
+pub struct ShiftRegister<L, C, D, const N: usize> {
+    load: L,
+    clock: C,
+    data: D,
+    keys: [Key; N],
+}
+
+impl<L, C, D, const N: usize> ShiftRegister<L, C, D, N>
+where
+    D: InputPin,
+    L: OutputPin<Error = D::Error>,
+    C: OutputPin<Error = D::Error>,
+{
+    pub fn new(load: L, clock: C, data: D, keys: [Key; N]) -> Self {
+        ShiftRegister {
+            load,
+            clock,
+            data,
+            keys,
+        }
+    }
+}
+
+impl<L, C, D, const N: usize> Scan<N> for ShiftRegister<L, C, D, N>
+where
+    D: InputPin,
+    L: OutputPin<Error = D::Error>,
+    C: OutputPin<Error = D::Error>,
+{
+    type Error = D::Error;
+
+    fn scan(&mut self) -> Result<[bool; N], Self::Error> {
+        let mut samples = [false; N];
+        self.clock.set_low()?;
+        // latch the parallel inputs
+        self.load.set_low()?;
+        self.load.set_high()?;
+        for ix in (0..N).rev() {
+            samples[ix] = self.data.is_low()?;
+            self.clock.set_high()?;
+            self.clock.set_low()?;
+        }
+        Ok(samples)
+    }
+
+    fn keys(&self) -> [Key; N] {
+        self.keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+    use crate::debounce::{Algorithm, Debouncer};
+    use crate::lex::{qwerty::*, Event};
+
+    // Two sets of lines joined by switches, a line is low while driven low or
+    // connected through a closed switch to a line that is driven low
+    #[derive(Default)]
+    struct Wires {
+        driven_low: [bool; 4],
+        closed: [[bool; 4]; 4],
+        shifted: Vec<bool>,
+        bit: usize,
+    }
+
+    struct Strobe(Rc<RefCell<Wires>>, usize);
+    struct Sense(Rc<RefCell<Wires>>, usize);
+
+    impl ErrorType for Strobe {
+        type Error = Infallible;
+    }
+    impl ErrorType for Sense {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Strobe {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().driven_low[self.1] = true;
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().driven_low[self.1] = false;
+            Ok(())
+        }
+    }
+
+    impl InputPin for Sense {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            let wires = self.0.borrow();
+            Ok(!(0..4).any(|out| wires.driven_low[out] && wires.closed[out][self.1]))
+        }
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.is_high()?)
+        }
+    }
+
+    fn matrix_pins(wires: &Rc<RefCell<Wires>>) -> ([Strobe; 2], [Sense; 3]) {
+        (
+            [Strobe(wires.clone(), 0), Strobe(wires.clone(), 1)],
+            [
+                Sense(wires.clone(), 0),
+                Sense(wires.clone(), 1),
+                Sense(wires.clone(), 2),
+            ],
+        )
+    }
+
+    #[test]
+    fn matrix_col2row() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let (rows, cols) = matrix_pins(&wires);
+        let mut matrix = Matrix::col2row(rows, cols, [(0, 0, Q.0), (0, 2, E.0), (1, 1, S.0)]);
+        assert_eq!([Q.0, E.0, S.0], matrix.keys());
+        assert_eq!([false, false, false], matrix.scan().unwrap());
+
+        wires.borrow_mut().closed[0][2] = true;
+        wires.borrow_mut().closed[1][1] = true;
+        assert_eq!([false, true, true], matrix.scan().unwrap());
+        // strobes are released after each scan
+        assert_eq!([false; 4], wires.borrow().driven_low);
+    }
+
+    #[test]
+    fn matrix_row2col() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        // three rows read, two columns strobed
+        let (cols, rows) = matrix_pins(&wires);
+        let mut matrix = Matrix::row2col(rows, cols, [(0, 0, Q.0), (2, 0, Z.0), (1, 1, S.0)]);
+
+        // switch at row 2, col 0 is out 0, in 2
+        wires.borrow_mut().closed[0][2] = true;
+        assert_eq!([false, true, false], matrix.scan().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn matrix_position_outside() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let (rows, cols) = matrix_pins(&wires);
+        Matrix::col2row(rows, cols, [(2, 0, Q.0)]);
+    }
+
+    #[test]
+    fn direct_pins() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        // a direct pin is a switch to ground, here the always driven line 0
+        wires.borrow_mut().driven_low[0] = true;
+        let (_, pins) = matrix_pins(&wires);
+        let mut direct = DirectPins::new(pins, [Q.0, W.0, E.0]);
+        wires.borrow_mut().closed[0][1] = true;
+        assert_eq!([false, true, false], direct.scan().unwrap());
+        assert_eq!([Q.0, W.0, E.0], direct.keys());
+    }
+
+    struct Load(Rc<RefCell<Wires>>);
+    struct Clock(Rc<RefCell<Wires>>);
+    struct Data(Rc<RefCell<Wires>>);
+
+    impl ErrorType for Load {
+        type Error = Infallible;
+    }
+    impl ErrorType for Clock {
+        type Error = Infallible;
+    }
+    impl ErrorType for Data {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Load {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut wires = self.0.borrow_mut();
+            wires.bit = 0;
+            wires.shifted = wires.closed[0].iter().rev().copied().collect();
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl OutputPin for Clock {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().bit += 1;
+            Ok(())
+        }
+    }
+
+    impl InputPin for Data {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            let wires = self.0.borrow();
+            Ok(!wires.shifted[wires.bit])
+        }
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.is_high()?)
+        }
+    }
+
+    #[test]
+    fn shift_register() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let mut register = ShiftRegister::new(
+            Load(wires.clone()),
+            Clock(wires.clone()),
+            Data(wires.clone()),
+            [Q.0, W.0, E.0, R.0],
+        );
+        wires.borrow_mut().closed[0] = [false, true, false, true];
+        assert_eq!([false, true, false, true], register.scan().unwrap());
+        wires.borrow_mut().closed[0] = [true, false, false, false];
+        assert_eq!([true, false, false, false], register.scan().unwrap());
+    }
+
+    #[test]
+    fn scan_into_events() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let (rows, cols) = matrix_pins(&wires);
+        let mut matrix = Matrix::col2row(rows, cols, [(0, 0, Q.0), (0, 1, W.0)]);
+        let mut debouncer = Debouncer::new(matrix.keys(), Algorithm::Eager, 5);
+
+        wires.borrow_mut().closed[0][1] = true;
+        let events = debouncer.update(&matrix.scan().unwrap(), 0);
+        assert_eq!(&[Event::Down(W.0)], events.as_slice());
+        wires.borrow_mut().closed[0][1] = false;
+        let events = debouncer.update(&matrix.scan().unwrap(), 10);
+        assert_eq!(&[Event::Up(W.0)], events.as_slice());
+    }
+
+    fn ghost_matrix(wires: &Rc<RefCell<Wires>>) -> Matrix<Sense, Strobe, 2, 3, 6> {
+        let (rows, cols) = matrix_pins(wires);
+        Matrix::col2row(
+            rows,
+            cols,
+            [
+                (0, 0, Q.0),
+                (0, 1, W.0),
+                (0, 2, E.0),
+                (1, 0, A.0),
+                (1, 1, S.0),
+                (1, 2, D.0),
+            ],
+        )
+    }
+
+    #[test]
+    fn ghost_is_suppressed() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let mut matrix = ghost_matrix(&wires);
+        let mut ghosts: std::vec::Vec<Ghost> = std::vec::Vec::new();
+
+        wires.borrow_mut().closed[0][0] = true;
+        wires.borrow_mut().closed[0][1] = true;
+        let samples = matrix.scan_deghosted(&mut |g| ghosts.push(g)).unwrap();
+        assert_eq!([true, true, false, false, false, false], samples);
+
+        // pressing A makes S appear as well
+        wires.borrow_mut().closed[1][0] = true;
+        wires.borrow_mut().closed[1][1] = true;
+        let samples = matrix.scan_deghosted(&mut |g| ghosts.push(g)).unwrap();
+        assert_eq!([true, true, false, false, false, false], samples);
+        assert_eq!(
+            &[Ghost {
+                rows: (0, 1),
+                cols: (0, 1)
+            }],
+            ghosts.as_slice()
+        );
+
+        // releasing W breaks the rectangle, A is let through
+        wires.borrow_mut().closed[0][1] = false;
+        wires.borrow_mut().closed[1][1] = false;
+        let samples = matrix.scan_deghosted(&mut |g| ghosts.push(g)).unwrap();
+        assert_eq!([true, false, false, true, false, false], samples);
+        assert_eq!(1, ghosts.len());
+    }
+
+    #[test]
+    fn no_ghost_without_rectangle() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let mut matrix = ghost_matrix(&wires);
+
+        for (row, col) in [(0, 0), (0, 1), (0, 2), (1, 2)] {
+            wires.borrow_mut().closed[row][col] = true;
+        }
+        let samples = matrix
+            .scan_deghosted(&mut |g| panic!("Unexpected {:?}", g))
+            .unwrap();
+        assert_eq!([true, true, true, false, false, true], samples);
+    }
+
+    #[test]
+    fn ghost_row2col() {
+        let wires = Rc::new(RefCell::new(Wires::default()));
+        let (cols, rows) = matrix_pins(&wires);
+        let mut matrix = Matrix::row2col(rows, cols, [(0, 0, Q.0), (2, 1, C.0)]);
+        let mut ghosts: std::vec::Vec<Ghost> = std::vec::Vec::new();
+
+        for (out, inp) in [(0, 0), (0, 2), (1, 0), (1, 2)] {
+            wires.borrow_mut().closed[out][inp] = true;
+        }
+        let samples = matrix.scan_deghosted(&mut |g| ghosts.push(g)).unwrap();
+        assert_eq!([false, false], samples);
+        assert_eq!(
+            &[Ghost {
+                rows: (0, 2),
+                cols: (0, 1)
+            }],
+            ghosts.as_slice()
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    behavior::{Behavior, Context},
+    lex::{bit, Event, Key, Millis, Pressed},
+    parse::{leaf, Emit, Rules},
+    pipeline::Stage,
+};
+
+// The swap hands action, bind it to a chord with Emit::Custom. It swaps while
+// the keys of the chord are held, so that chord should be one no further key
+// can change, or as a toggle each press turns it on or off
+pub struct Swap {
+    toggle: bool,
+    active: AtomicBool,
+}
+
+impl Swap {
+    pub const fn new() -> Self {
+        Swap {
+            toggle: false,
+            active: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn toggle(self) -> Self {
+        Swap {
+            toggle: true,
+            ..self
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Swap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Behavior for Swap {
+    fn press(&self, _ctx: &mut Context) {
+        let active = !self.toggle || !self.is_active();
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    fn release(&self, _ctx: &mut Context) {
+        if !self.toggle {
+            self.active.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+// While the swap action is on, keys reach the rules from the other half:
+// Left(id) is Right(id) and back. The table pairs keys that do not share an
+// id, for boards whose halves differ
+pub struct SwapHands<'a> {
+    rules: Rules<'a, Keyb>,
+    swap: &'static Swap,
+    table: &'static [(Pressed, Pressed)],
+    // keys that went down mirrored come up mirrored, whatever the swap did since
+    mirrored: u128,
+}
+
+impl<'a> SwapHands<'a> {
+    // The rules are those of the engine, keys bound to the swap action on
+    // their own are never mirrored so a toggle can turn it off again
+    pub const fn new(rules: Rules<'a, Keyb>, swap: &'static Swap) -> Self {
+        SwapHands {
+            rules,
+            swap,
+            table: &[],
+            mirrored: 0,
+        }
+    }
+
+    pub const fn table(self, table: &'static [(Pressed, Pressed)]) -> Self {
+        SwapHands { table, ..self }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.swap.is_active()
+    }
+
+    // A table entry pairs its keys, their partners by id keep their place so
+    // no two keys mirror to the same one
+    fn mirror(&self, key: Key) -> Key {
+        if let Some(paired) = self.paired(key) {
+            return paired;
+        }
+        let by_id = match key {
+            Key::Left(id) => Key::Right(id),
+            Key::Right(id) => Key::Left(id),
+        };
+        match self.paired(by_id) {
+            Some(_) => key,
+            None => by_id,
+        }
+    }
+
+    fn paired(&self, key: Key) -> Option<Key> {
+        self.table.iter().find_map(|(a, b)| match key {
+            _ if a.0 == key => Some(b.0),
+            _ if b.0 == key => Some(a.0),
+            _ => None,
+        })
+    }
+
+    fn swaps(&self, key: Key) -> bool {
+        let swap: &dyn Behavior = self.swap;
+        leaf(self.rules.parse(&[Pressed(key)])) == Emit::Custom(swap)
+    }
+}
+
+impl Stage for SwapHands<'_> {
+    fn events<const N: usize>(&mut self, events: &mut Vec<Event, N>, _now: Millis) {
+        for event in events.iter_mut() {
+            *event = match *event {
+                Event::Down(key) if self.is_active() && !self.swaps(key) => {
+                    self.mirrored |= bit(key);
+                    Event::Down(self.mirror(key))
+                }
+                Event::Up(key) if self.mirrored & bit(key) != 0 => {
+                    self.mirrored &= !bit(key);
+                    Event::Up(self.mirror(key))
+                }
+                event => event,
+            };
+        }
+    }
+
+    fn clear(&mut self) {
+        self.mirrored = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alias,
+        engine::Engine,
+        lex::{qwerty::*, Event::*},
+        parse::{ChordEmit, ChordEvent, ChordEvent::On, Emit::*},
+    };
+
+    alias!(SWAP, Left, K18);
+
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+    const P_EVENTS: [ChordEvent; 1] = [On(P)];
+    const O_EVENTS: [ChordEvent; 1] = [On(O)];
+    const W_EVENTS: [ChordEvent; 1] = [On(W)];
+    const SWAP_EVENTS: [ChordEvent; 1] = [On(SWAP)];
+
+    // Each test has an action of its own, tests run at once
+    const fn rules(swap: &'static Swap) -> [ChordEmit<Keyb>; 5] {
+        [
+            ChordEmit::new(&SWAP_EVENTS, Custom(swap)),
+            ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+            ChordEmit::new(&P_EVENTS, Code(Keyb::P)),
+            ChordEmit::new(&O_EVENTS, Code(Keyb::O)),
+            ChordEmit::new(&W_EVENTS, Code(Keyb::W)),
+        ]
+    }
+
+    static HELD: Swap = Swap::new();
+    static HELD_RULES: [ChordEmit<Keyb>; 5] = rules(&HELD);
+    static TOGGLE: Swap = Swap::new().toggle();
+    static TOGGLE_RULES: [ChordEmit<Keyb>; 5] = rules(&TOGGLE);
+    static PAIRED: Swap = Swap::new();
+    static PAIRED_RULES: [ChordEmit<Keyb>; 5] = rules(&PAIRED);
+
+    fn engine(
+        rules: &'static [ChordEmit<Keyb>],
+        swap: &'static Swap,
+        table: &'static [(Pressed, Pressed)],
+    ) -> Engine<'static, 16, 16, SwapHands<'static>> {
+        let rules = Rules::ordered(rules);
+        Engine::new(rules).pipeline(SwapHands::new(rules, swap).table(table))
+    }
+
+    fn tap(engine: &mut Engine<'static, 16, 16, SwapHands<'static>>, key: Pressed) {
+        engine.feed(Down(key.0));
+        engine.feed(Up(key.0));
+    }
+
+    #[test]
+    fn while_held() {
+        let mut engine = engine(&HELD_RULES, &HELD, &[]);
+        engine.feed(Down(SWAP.0));
+        assert!(engine.stages().is_active());
+        tap(&mut engine, Q);
+        tap(&mut engine, P);
+        // held across the release of the swap key
+        engine.feed(Down(Q.0));
+        engine.feed(Up(SWAP.0));
+        assert!(!engine.stages().is_active());
+        engine.feed(Up(Q.0));
+        tap(&mut engine, Q);
+        assert_eq!(
+            &[
+                Keyb::P,
+                Keyb::Out,
+                Keyb::Q,
+                Keyb::Out,
+                Keyb::P,
+                Keyb::Out,
+                Keyb::Q
+            ],
+            engine.drain_reports().as_slice()
+        );
+        assert!(!engine.is_held(P.0));
+    }
+
+    #[test]
+    fn toggled() {
+        let mut engine = engine(&TOGGLE_RULES, &TOGGLE, &[]);
+        tap(&mut engine, SWAP);
+        assert!(engine.stages().is_active());
+        tap(&mut engine, Q);
+        tap(&mut engine, SWAP);
+        assert!(!engine.stages().is_active());
+        tap(&mut engine, Q);
+        assert_eq!(
+            &[Keyb::P, Keyb::Out, Keyb::Q],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn table() {
+        // Q pairs with O, their partners by id P and W stay in place
+        let mut engine = engine(&PAIRED_RULES, &PAIRED, &[(Q, O)]);
+        engine.feed(Down(SWAP.0));
+        for key in [Q, O, P, W] {
+            tap(&mut engine, key);
+        }
+        assert_eq!(
+            &[
+                Keyb::O,
+                Keyb::Out,
+                Keyb::Q,
+                Keyb::Out,
+                Keyb::P,
+                Keyb::Out,
+                Keyb::W
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use usbd_human_interface_device::page::Keyboard as Keyb;
+
+use crate::{
+    behavior::{Behavior, Context},
+    lex::{Event, Key, Millis},
+    parse::Emit,
+};
+
+// Taps of the same chord within the timeout of each other count up, once the
+// timeout passes the count picks the emit. Bind it with Emit::Custom. The rule
+// table maps a chord to one emit and a count is no chord, so the emits per
+// count live here; they are reported like any rule emit
+pub struct TapDance {
+    timeout: Millis,
+    // single, double and triple tap, a count without an emit repeats the single
+    // tap up to three times
+    taps: [Option<Emit<Keyb>>; 3],
+    // tapped, then pressed again and still held when the timeout passes
+    hold: Option<Emit<Keyb>>,
+    count: AtomicU32,
+    last: AtomicU32,
+    key: AtomicU8,
+    // the release of a resolved hold is not a tap
+    swallow: AtomicBool,
+    // the last press came on Down, its key is still held
+    early: AtomicBool,
+    // pressed again after a tap that resolved on release, the hold counts from there
+    again: AtomicBool,
+}
+
+impl TapDance {
+    pub const fn new(timeout: Millis, tap: Emit<Keyb>) -> Self {
+        TapDance {
+            timeout,
+            taps: [Some(tap), None, None],
+            hold: None,
+            count: AtomicU32::new(0),
+            last: AtomicU32::new(0),
+            key: AtomicU8::new(0),
+            swallow: AtomicBool::new(false),
+            early: AtomicBool::new(false),
+            again: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn double(self, emit: Emit<Keyb>) -> Self {
+        TapDance {
+            taps: [self.taps[0], Some(emit), self.taps[2]],
+            ..self
+        }
+    }
+
+    pub const fn triple(self, emit: Emit<Keyb>) -> Self {
+        TapDance {
+            taps: [self.taps[0], self.taps[1], Some(emit)],
+            ..self
+        }
+    }
+
+    pub const fn hold(self, emit: Emit<Keyb>) -> Self {
+        TapDance {
+            hold: Some(emit),
+            ..self
+        }
+    }
+}
+
+impl Behavior for TapDance {
+    fn press(&self, ctx: &mut Context) {
+        if self.swallow.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let Some(first) = ctx.chord.first() else {
+            return;
+        };
+        self.key
+            .store(u8::from(Event::Up(first.0)), Ordering::Relaxed);
+        self.early.store(ctx.is_held(first.0), Ordering::Relaxed);
+        self.again.store(false, Ordering::Relaxed);
+        self.last.store(ctx.now, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn tick(&self, ctx: &mut Context) -> bool {
+        let count = self.count.load(Ordering::Relaxed) as usize;
+        if count == 0 {
+            return false;
+        }
+        let key = Key::from(Event::from(self.key.load(Ordering::Relaxed)));
+        // a chord that resolves on release makes no press for the key going
+        // down again, its first tick held stands in
+        if ctx.is_held(key)
+            && !self.early.load(Ordering::Relaxed)
+            && !self.again.swap(true, Ordering::Relaxed)
+        {
+            self.last.store(ctx.now, Ordering::Relaxed);
+        }
+        if ctx.now.wrapping_sub(self.last.load(Ordering::Relaxed)) < self.timeout {
+            return true;
+        }
+        self.count.store(0, Ordering::Relaxed);
+        if let Some(hold) = self.hold.filter(|_| ctx.is_held(key)) {
+            // a chord that resolves on release presses once more
+            let early = self.early.load(Ordering::Relaxed);
+            self.swallow.store(!early, Ordering::Relaxed);
+            ctx.emit(hold, key);
+            return false;
+        }
+        let count = count.min(self.taps.len());
+        match self.taps[count - 1] {
+            Some(emit) => ctx.emit(emit, key),
+            None => {
+                for _ in 0..count {
+                    ctx.emit(self.taps[0].unwrap(), key);
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alias,
+        engine::Engine,
+        lex::{qwerty::Q, Event::*, Pressed},
+        parse::{ChordEmit, ChordEvent, ChordEvent::On, Emit::*, Rules},
+    };
+
+    alias!(BCK, Left, K17);
+
+    const BCK_EVENTS: [ChordEvent; 1] = [On(BCK)];
+    const Q_EVENTS: [ChordEvent; 1] = [On(Q)];
+
+    // Dances keep their state in statics, so every test has its own
+    macro_rules! bck_dance {
+        () => {{
+            // double tap deletes a word
+            static DANCE: TapDance = TapDance::new(200, Code(Keyb::DeleteBackspace))
+                .double(Ctrl(&Code(Keyb::DeleteBackspace)))
+                .triple(Ctrl(&Shift(&Code(Keyb::DeleteBackspace))))
+                .hold(Code(Keyb::DeleteForward));
+            const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&BCK_EVENTS, Custom(&DANCE))];
+            &RULES
+        }};
+    }
+
+    // Taps the key at each time, then lets the clock run out
+    fn dance(rules: &[ChordEmit<Keyb>], key: Pressed, taps: &[Millis]) -> heapless::Vec<Keyb, 16> {
+        let mut engine: Engine<16, 16> = Engine::new(Rules::ordered(rules));
+        let mut out = heapless::Vec::new();
+        let mut drain = |engine: &mut Engine<16, 16>| {
+            let reports = engine.drain_reports();
+            if !out.is_empty() && !reports.is_empty() {
+                out.push(Keyb::Out).unwrap();
+            }
+            out.extend(reports);
+        };
+        for at in taps {
+            engine.tick(*at);
+            drain(&mut engine);
+            engine.feed(Down(key.into()));
+            engine.tick(at + 20);
+            engine.feed(Up(key.into()));
+        }
+        let end = taps.last().unwrap() + 20;
+        for now in (end..end + 300).step_by(10) {
+            engine.tick(now);
+        }
+        drain(&mut engine);
+        out
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(
+            &[Keyb::DeleteBackspace],
+            dance(bck_dance!(), BCK, &[0]).as_slice()
+        );
+        assert_eq!(
+            &[Keyb::LeftControl, Keyb::DeleteBackspace],
+            dance(bck_dance!(), BCK, &[0, 100]).as_slice()
+        );
+        assert_eq!(
+            &[Keyb::LeftControl, Keyb::LeftShift, Keyb::DeleteBackspace],
+            dance(bck_dance!(), BCK, &[0, 100, 200]).as_slice()
+        );
+        // more than three taps stay a triple tap
+        assert_eq!(
+            &[Keyb::LeftControl, Keyb::LeftShift, Keyb::DeleteBackspace],
+            dance(bck_dance!(), BCK, &[0, 100, 200, 300]).as_slice()
+        );
+    }
+
+    #[test]
+    fn timeout_splits_dances() {
+        assert_eq!(
+            &[Keyb::DeleteBackspace, Keyb::Out, Keyb::DeleteBackspace],
+            dance(bck_dance!(), BCK, &[0, 500]).as_slice()
+        );
+    }
+
+    #[test]
+    fn missing_count_repeats_tap() {
+        static DANCE: TapDance = TapDance::new(200, Code(Keyb::Q));
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Q_EVENTS, Custom(&DANCE))];
+        assert_eq!(
+            &[Keyb::Q, Keyb::Out, Keyb::Q],
+            dance(&RULES, Q, &[0, 100]).as_slice()
+        );
+    }
+
+    #[test]
+    fn many_taps_without_emits() {
+        static DANCE: TapDance = TapDance::new(200, Code(Keyb::Q));
+        const RULES: [ChordEmit<Keyb>; 1] = [ChordEmit::new(&Q_EVENTS, Custom(&DANCE))];
+        let taps: std::vec::Vec<Millis> = (0..20).map(|n| n * 50).collect();
+        assert_eq!(
+            &[Keyb::Q, Keyb::Out, Keyb::Q, Keyb::Out, Keyb::Q],
+            dance(&RULES, Q, &taps).as_slice()
+        );
+    }
+
+    #[test]
+    fn repeated() {
+        alias!(REP, Right, K17);
+        const REP_EVENTS: [ChordEvent; 1] = [On(REP)];
+        const RULES: [ChordEmit<Keyb>; 3] = [
+            ChordEmit::new(&BCK_EVENTS, Custom(&DANCE)),
+            ChordEmit::new(&Q_EVENTS, Code(Keyb::Q)),
+            ChordEmit::new(&REP_EVENTS, Repeat),
+        ];
+        static DANCE: TapDance = TapDance::new(200, Code(Keyb::B)).double(Ctrl(&Code(Keyb::B)));
+        let mut engine: Engine<16, 16> = Engine::new(Rules::ordered(&RULES));
+        for (key, at) in [(Q, 0), (BCK, 100), (BCK, 150)] {
+            engine.tick(at);
+            engine.feed(Down(key.into()));
+            engine.feed(Up(key.into()));
+        }
+        engine.tick(500);
+        engine.feed(Down(REP.into()));
+        engine.feed(Up(REP.into()));
+        // the dance went out last
+        assert_eq!(
+            &[
+                Keyb::Q,
+                Keyb::Out,
+                Keyb::LeftControl,
+                Keyb::B,
+                Keyb::Out,
+                Keyb::LeftControl,
+                Keyb::B
+            ],
+            engine.drain_reports().as_slice()
+        );
+    }
+
+    #[test]
+    fn hold_counts_from_second_press() {
+        static DANCE: TapDance =
+            TapDance::new(200, Code(Keyb::DeleteBackspace)).hold(Code(Keyb::DeleteForward));
+        // BCK+Q keeps BCK from resolving on press
+        const BCK_Q_EVENTS: [ChordEvent; 2] = [On(BCK), On(Q)];
+        const RULES: [ChordEmit<Keyb>; 2] = [
+            ChordEmit::new(&BCK_Q_EVENTS, Code(Keyb::Escape)),
+            ChordEmit::new(&BCK_EVENTS, Custom(&DANCE)),
+        ];
+        let mut engine: Engine<16, 16> = Engine::new(Rules::ordered(&RULES));
+        engine.feed(Down(BCK.into()));
+        engine.tick(20);
+        engine.feed(Up(BCK.into()));
+        engine.tick(150);
+        engine.feed(Down(BCK.into()));
+        for now in (150..340).step_by(10) {
+            engine.tick(now);
+        }
+        assert!(engine.drain_reports().is_empty());
+        engine.tick(350);
+        assert_eq!(&[Keyb::DeleteForward], engine.drain_reports().as_slice());
+        engine.feed(Up(BCK.into()));
+        for now in (360..800).step_by(10) {
+            engine.tick(now);
+        }
+        assert!(engine.drain_reports().is_empty());
+    }
+
+    #[test]
+    fn tap_then_hold() {
+        let mut engine: Engine<16, 16> = Engine::new(Rules::ordered(bck_dance!()));
+        engine.feed(Down(BCK.into()));
+        engine.feed(Up(BCK.into()));
+        engine.tick(50);
+        engine.feed(Down(BCK.into()));
+        for now in (60..400).step_by(10) {
+            engine.tick(now);
+        }
+        assert_eq!(&[Keyb::DeleteForward], engine.drain_reports().as_slice());
+        // the release after the hold is no new tap
+        engine.feed(Up(BCK.into()));
+        for now in (400..800).step_by(10) {
+            engine.tick(now);
+        }
+        assert!(engine.drain_reports().is_empty());
+        // the next one is
+        engine.feed(Down(BCK.into()));
+        engine.feed(Up(BCK.into()));
+        for now in (800..1200).step_by(10) {
+            engine.tick(now);
+        }
+        assert_eq!(&[Keyb::DeleteBackspace], engine.drain_reports().as_slice());
+    }
+}
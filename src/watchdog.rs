@@ -0,0 +1,235 @@
+use heapless::Vec;
+
+use crate::lex::{Event, Key, Millis, PRESS_SIZE};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    // Held for longer than the watchdog allows, released with a synthetic Up
+    Stuck(Key),
+    // Went down again while still held, so its Up was lost
+    Orphaned(Key),
+    // Went up without being held, dropped before reaching the stack
+    Stray(Key),
+    // Stack ran full behind an unfinished root, root was dropped
+    Overflow(Key),
+}
+
+pub struct Watchdog {
+    max_hold: Millis,
+    held: Vec<(Key, Millis), PRESS_SIZE>,
+}
+
+impl Watchdog {
+    pub fn new(max_hold: Millis) -> Self {
+        Watchdog {
+            max_hold,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn feed<F: FnMut(Recovery), const S: usize>(
+        &mut self,
+        stack: &mut Vec<Event, S>,
+        event: Event,
+        now: Millis,
+        diagnostics: &mut F,
+    ) {
+        match event {
+            Event::Down(key) => {
+                if self.forget(key) {
+                    release(stack, key);
+                    diagnostics(Recovery::Orphaned(key));
+                }
+                if self.held.is_full() {
+                    let (oldest, _) = self.held.remove(0);
+                    release(stack, oldest);
+                    diagnostics(Recovery::Stuck(oldest));
+                }
+                self.held.push((key, now)).unwrap();
+            }
+            Event::Up(key) => {
+                if !self.forget(key) {
+                    diagnostics(Recovery::Stray(key));
+                    return;
+                }
+            }
+        }
+        if stack.is_full() {
+            if let Some(Event::Down(root)) = stack.first().copied() {
+                stack.retain(|e| Key::from(*e) != root);
+                self.forget(root);
+                diagnostics(Recovery::Overflow(root));
+            } else {
+                stack.clear();
+            }
+        }
+        if stack.push(event).is_err() {
+            panic!("Should have enough capacity to push on stack");
+        }
+    }
+
+    pub fn tick<F: FnMut(Recovery), const S: usize>(
+        &mut self,
+        stack: &mut Vec<Event, S>,
+        now: Millis,
+        diagnostics: &mut F,
+    ) {
+        while let Some(ix) = self
+            .held
+            .iter()
+            .position(|(_, since)| now.wrapping_sub(*since) > self.max_hold)
+        {
+            let (key, _) = self.held.remove(ix);
+            release(stack, key);
+            diagnostics(Recovery::Stuck(key));
+        }
+    }
+
+    // The key went up without its Up reaching feed, e.g. swallowed on the way.
+    // True when it was held
+    pub fn forget(&mut self, key: Key) -> bool {
+        match self.held.iter().position(|(held, _)| *held == key) {
+            Some(ix) => {
+                self.held.remove(ix);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// A key whose Down already left the stack has nothing to release there
+fn release<const S: usize>(stack: &mut Vec<Event, S>, key: Key) {
+    if !stack.contains(&Event::Down(key)) {
+        return;
+    }
+    if stack.is_full() {
+        // No room for the Up, drop the key from the stack instead
+        stack.retain(|e| Key::from(*e) != key);
+    } else {
+        stack.push(Event::Up(key)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex::STACK_SIZE;
+    use crate::lex::{chord, qwerty::*, Pressed};
+    use Event::*;
+
+    fn feed(dog: &mut Watchdog, stack: &mut Vec<Event, STACK_SIZE>, event: Event, now: Millis) {
+        dog.feed(stack, event, now, &mut |r| panic!("Unexpected {:?}", r));
+    }
+
+    #[test]
+    fn healthy_keys_pass_through() {
+        let mut dog = Watchdog::new(1000);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        feed(&mut dog, &mut stack, Down(Q.into()), 0);
+        feed(&mut dog, &mut stack, Up(Q.into()), 10);
+        dog.tick(&mut stack, 5000, &mut |r| panic!("Unexpected {:?}", r));
+
+        assert_eq!(&[Down(Q.into()), Up(Q.into())], stack.as_slice());
+    }
+
+    #[test]
+    fn stuck_key_is_released() {
+        let mut dog = Watchdog::new(1000);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut recovered: Vec<Recovery, 4> = Vec::new();
+        feed(&mut dog, &mut stack, Down(Q.into()), 0);
+        feed(&mut dog, &mut stack, Down(W.into()), 10);
+        feed(&mut dog, &mut stack, Up(W.into()), 20);
+        assert!(chord(&mut stack).is_empty());
+
+        dog.tick(&mut stack, 500, &mut |r| recovered.push(r).unwrap());
+        assert!(recovered.is_empty());
+        dog.tick(&mut stack, 1001, &mut |r| recovered.push(r).unwrap());
+        assert_eq!(&[Recovery::Stuck(Q.into())], recovered.as_slice());
+
+        let presses = chord(&mut stack);
+        assert_eq!(&[Q, W], presses.as_slice());
+        assert!(stack.is_empty());
+
+        // The real Up eventually shows up and is dropped
+        dog.feed(&mut stack, Up(Q.into()), 1500, &mut |r| {
+            recovered.push(r).unwrap()
+        });
+        assert_eq!(Some(&Recovery::Stray(Q.into())), recovered.last());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn orphaned_down_is_released() {
+        let mut dog = Watchdog::new(1000);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut recovered: Vec<Recovery, 4> = Vec::new();
+        feed(&mut dog, &mut stack, Down(Q.into()), 0);
+        // Up(Q) lost on the wire
+        dog.feed(&mut stack, Down(Q.into()), 100, &mut |r| {
+            recovered.push(r).unwrap()
+        });
+        assert_eq!(&[Recovery::Orphaned(Q.into())], recovered.as_slice());
+        assert_eq!(&[Q], chord(&mut stack).as_slice());
+        assert_eq!(&[Down(Q.into())], stack.as_slice());
+
+        feed(&mut dog, &mut stack, Up(Q.into()), 110);
+        assert_eq!(&[Q], chord(&mut stack).as_slice());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn stuck_off_the_stack() {
+        let mut dog = Watchdog::new(1000);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut recovered: Vec<Recovery, 4> = Vec::new();
+        feed(&mut dog, &mut stack, Down(Q.into()), 0);
+        stack.clear();
+        feed(&mut dog, &mut stack, Down(W.into()), 1500);
+        dog.tick(&mut stack, 1500, &mut |r| recovered.push(r).unwrap());
+        // no lone Up(Q) ahead of W
+        assert_eq!(&[Recovery::Stuck(Q.into())], recovered.as_slice());
+        assert_eq!(&[Down(W.into())], stack.as_slice());
+
+        // nor once it was forgotten
+        feed(&mut dog, &mut stack, Down(E.into()), 1500);
+        assert!(dog.forget(E.into()));
+        dog.tick(&mut stack, 5000, &mut |r| recovered.push(r).unwrap());
+        assert_eq!(Some(&Recovery::Stuck(W.into())), recovered.last());
+    }
+
+    #[test]
+    fn stray_up_is_dropped() {
+        let mut dog = Watchdog::new(1000);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut recovered: Vec<Recovery, 4> = Vec::new();
+        dog.feed(&mut stack, Up(Q.into()), 0, &mut |r| {
+            recovered.push(r).unwrap()
+        });
+
+        assert!(stack.is_empty());
+        assert_eq!(&[Recovery::Stray(Q.into())], recovered.as_slice());
+    }
+
+    #[test]
+    fn overflow_drops_root() {
+        let mut dog = Watchdog::new(Millis::MAX);
+        let mut stack: Vec<Event, STACK_SIZE> = Vec::new();
+        let mut recovered: Vec<Recovery, 4> = Vec::new();
+        feed(&mut dog, &mut stack, Down(Q.into()), 0);
+        for i in 0..(STACK_SIZE as u32 - 1) / 2 {
+            feed(&mut dog, &mut stack, Down(W.into()), i);
+            feed(&mut dog, &mut stack, Up(W.into()), i);
+        }
+        feed(&mut dog, &mut stack, Down(R.into()), 100);
+        assert!(stack.is_full());
+
+        dog.feed(&mut stack, Down(E.into()), 200, &mut |r| {
+            recovered.push(r).unwrap()
+        });
+        assert_eq!(&[Recovery::Overflow(Q.into())], recovered.as_slice());
+        assert_eq!(Some(&Down(W.into())), stack.first());
+        assert_eq!(&[Pressed(W.into())], chord(&mut stack).as_slice());
+    }
+}